@@ -1,24 +1,28 @@
 use crate::basic::mempool::Mempool;
-use crate::crypto::merkle::{self, MerkleTree};
+use crate::basic::ledger::{BlockContext, Ledger};
 use crate::basic::block;
 use crate::blockchain::blockchain;
 use crate::crypto::hash::{H256, Hashable};
 use crate::network::server::Handle as ServerHandle;
 use crate::transaction;
-use std::collections::{HashMap, HashSet};
+use std::cmp;
+use std::collections::BinaryHeap;
 use std::sync::{Arc, Mutex};
 use log::{info, debug};
 use rand::Rng;
-use crate::basic::block::{Content, Header, Block};
-use crate::transaction::transaction::{SignedTransaction, Transaction};
+use crate::basic::block::{Coinbase, Content, Header, Block};
+use crate::api::address::H160 as Address;
+use crate::transaction::transaction::LedgerTransaction;
 use crate::blockchain::blockchain::Blockchain;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time::{self, SystemTime, UNIX_EPOCH};
 use std::{thread, mem};
 use crate::network::message::Message;
-use blockchain::Blockorigin;
+use blockchain::{Blockorigin, ReorgEvent};
 use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
 pub static END_GENERATOR:AtomicBool=AtomicBool::new(true);
+/// How many transactions the miner puts in a block; it waits until it has that many
+const BLOCK_TRANSACTIONS:usize=3;
 pub enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Exit,
@@ -36,6 +40,8 @@ pub struct Context {
     server:ServerHandle,
     blockchain:Arc<Mutex<Blockchain>>,
     mempool:Arc<Mutex<Mempool>>,
+    reorg_chan:Receiver<ReorgEvent>,
+    address:Address,//where the block rewards go
     total_num_mined: u64,
     start_time:Option<SystemTime>,
 }
@@ -50,8 +56,10 @@ pub fn new(
     server: &ServerHandle,
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool:&Arc<Mutex<Mempool>>,
+    address:Address,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let reorg_chan = blockchain.lock().unwrap().subscribe();
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
@@ -59,6 +67,8 @@ pub fn new(
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
         mempool:Arc::clone(mempool),
+        reorg_chan,
+        address,
         total_num_mined: 0,
         start_time:None
     };
@@ -113,7 +123,6 @@ impl Context {
                     info!("delay for every block {:?}",blockchain.all_block_delay());
                     let now_state=blockchain.get_tip_state();
                     info!("the mempool has {}",mempool.get_size());
-                    //info!("{:?}",mempool.hash_to_transaction);
                     info!("start to print now_state!");
                     info!("{}",now_state);
                     END_GENERATOR.fetch_or(true, Ordering::SeqCst);
@@ -130,14 +139,28 @@ impl Context {
         }
     }
 
+    /// Every attempt builds on the current tip, so a reorg only needs to be noted
+    fn note_reorg(&self, event: &ReorgEvent) {
+        if !event.disconnected.is_empty() {
+            info!("miner switching to a new fork, {} blocks were disconnected", event.disconnected.len());
+        }
+    }
+
     fn miner_loop(&mut self) {
         // main mining loop
         loop {
             // check and react to control signals
             match self.operating_state {
                 OperatingState::Paused => {
-                    let signal = self.control_chan.recv().unwrap();
-                    self.handle_control_signal(signal);
+                    // keep taking tip changes while paused, so they do not pile up in the channel
+                    crossbeam::select! {
+                        recv(self.control_chan) -> signal => self.handle_control_signal(signal.unwrap()),
+                        recv(self.reorg_chan) -> event => {
+                            if let Ok(event) = event {
+                                self.note_reorg(&event);
+                            }
+                        }
+                    }
                     continue;
                 }
                 OperatingState::ShutDown => {
@@ -160,53 +183,45 @@ impl Context {
                     let interval = time::Duration::from_micros(i as u64);
                     thread::sleep(interval);
                 }
+                for event in self.reorg_chan.try_iter() {
+                    self.note_reorg(&event);
+                }
                 let mut blockchain=self.blockchain.lock().unwrap();
                 let mut mempool=self.mempool.lock().unwrap();
-                //do if the mempool has 3 or more transactions
                 let parent=blockchain.tip();
                 let timestamp=SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-                let mut data:Vec<H256>=vec![];
                 let nonce:u32=rand::random();
-                let difficulty=blockchain.get_block(&parent).header.difficulty;
-                let mut transactions:Vec<SignedTransaction>=Vec::new();
-                
-                // let mut tx_tracker:HashSet<Vec<u8>>=HashSet::new();
-                //for every block, add 3 transactions into it
-                let mut transaction_delet:Vec<SignedTransaction>=Vec::new();
-                for (hash,transaction) in mempool.hash_to_transaction.iter(){
-                    if transactions.len()>=3{
-                        break;
-                    }
-                    let now_state=blockchain.get_tip_state();
-                    if transaction.verify_by_state(&now_state){
-                        transactions.push(transaction.clone());
-                        data.push(hash.clone());
-                    }
-                    else {
-                        transaction_delet.push(transaction.clone());
-                    }
-                }
-                mempool.remove_transaction(transaction_delet);
-                if data.len()<3{
-                    //debug!("data length is less than 3\n");
+                let difficulty=blockchain.expected_difficulty(&parent);
+                let height=blockchain.tip_height()+1;
+                let subsidy=blockchain.config().block_subsidy(height);
+                let coinbase=Coinbase{height,miner:self.address,value:subsidy};
+                let mut new_block=Block{
+                    header:Header{
+                        parent,
+                        nonce,
+                        difficulty,
+                        merkle_root:H256::default(),
+                        timestamp
+                    },
+                    content:Content{coinbase:Some(coinbase),transactions:Vec::new()}
+                };
+                // the size of everything but the transactions does not depend on what they are
+                let space=blockchain.config().max_block_size.saturating_sub(new_block.size());
+                let context=BlockContext{chain_id:blockchain.chain_id(),time:timestamp};
+                let transactions=select_transactions(&mempool,blockchain.tip_state(),&context,BLOCK_TRANSACTIONS,space);
+                //do if the mempool has 3 or more transactions
+                if transactions.len()<BLOCK_TRANSACTIONS{
                     drop(blockchain);
                     drop(mempool);
                     continue;
                 }
-                let merkle_root=MerkleTree::new(&data).root();
-                let new_block_header=Header{
-                    parent,
-                    nonce,
-                    difficulty,
-                    merkle_root,
-                    timestamp
-                };
                 let transaction_copy=transactions.clone();
-                let content=Content{transactions};
-                let new_block=Block{
-                    header:new_block_header,
-                    content
-                };
+                new_block.content.transactions=transactions;
+                let fees=new_block.content.total_fees();
+                if let Some(coinbase)=new_block.content.coinbase.as_mut(){
+                    coinbase.value=subsidy.saturating_add(fees);
+                }
+                new_block.header.merkle_root=new_block.content.merkle_root();
                 
                 if new_block.hash()<=difficulty{  
                     blockchain.insert(&new_block);
@@ -222,3 +237,125 @@ impl Context {
         }
     }
 }
+
+/// A transaction that may go into a block next, being the first pending one of its sender
+/// not picked yet
+struct Candidate<'a>{
+    transaction:&'a LedgerTransaction,
+    hash:H256,
+    size:usize,
+    queue:usize,//which sender's pending transactions it is from
+    position:usize,
+}
+impl Candidate<'_>{
+    fn new<'a>(pending:&[Vec<&'a LedgerTransaction>],queue:usize,position:usize)->Option<Candidate<'a>>{
+        let transaction=*pending[queue].get(position)?;
+        Some(Candidate{transaction,hash:transaction.hash(),size:transaction.size(),queue,position})
+    }
+}
+impl Ord for Candidate<'_>{
+    /// Higher fee per byte first, then by hash so the choice does not depend on the mempool's order
+    fn cmp(&self,other:&Self)->cmp::Ordering{
+        let rate=self.transaction.fee().base_units() as u128*other.size as u128;
+        let other_rate=other.transaction.fee().base_units() as u128*self.size as u128;
+        rate.cmp(&other_rate).then_with(|| other.hash.cmp(&self.hash))
+    }
+}
+impl PartialOrd for Candidate<'_>{
+    fn partial_cmp(&self,other:&Self)->Option<cmp::Ordering>{
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for Candidate<'_>{
+    fn eq(&self,other:&Self)->bool{
+        self.hash==other.hash
+    }
+}
+impl Eq for Candidate<'_>{}
+
+/// Pick up to `max_count` pending transactions of at most `max_bytes` in total that apply on
+/// `state` in the block described by `context`, by fee rate. A sender's transactions go in nonce order, so a transaction only competes
+/// once the one before it is in.
+pub fn select_transactions(mempool:&Mempool,state:&dyn Ledger,context:&BlockContext,max_count:usize,max_bytes:usize)->Vec<LedgerTransaction>{
+    let pending=mempool.pending();
+    let mut state=state.box_clone();
+    let mut candidates:BinaryHeap<Candidate>=(0..pending.len()).filter_map(|queue| Candidate::new(&pending,queue,0)).collect();
+    let mut selected=Vec::new();
+    let mut bytes=0;
+    while let Some(candidate)=candidates.pop(){
+        if selected.len()>=max_count{
+            break;
+        }
+        // a transaction that does not fit holds back the later ones of its sender
+        if bytes+candidate.size>max_bytes{
+            continue;
+        }
+        let transaction=candidate.transaction;
+        if !transaction.verify_signatures(&context.chain_id) || state.apply_transaction(transaction,context).is_err(){
+            continue;
+        }
+        bytes+=candidate.size;
+        selected.push(transaction.clone());
+        if let Some(next)=Candidate::new(&pending,candidate.queue,candidate.position+1){
+            candidates.push(next);
+        }
+    }
+    selected
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::basic::amount::Amount;
+    use crate::basic::state::State;
+    use crate::blockchain::blockchain::{ico_address, ico_key_pair};
+    use crate::transaction::transaction::{SignedTransaction, Transaction, TransactionKind};
+
+    fn amount(units:u64)->Amount{
+        Amount::from_base_units(units)
+    }
+
+    fn signed_transaction(sender:u8,nonce:usize,fee:u64)->LedgerTransaction{
+        let raw=Transaction{sender:ico_address(sender),receiver:ico_address(9),nonce,value:amount(10),fee:amount(fee),valid_until_height:None,kind:TransactionKind::Transfer,memo:Vec::new()};
+        SignedTransaction::from_raw(raw,&Block::genesis().hash(),&ico_key_pair(sender)).into()
+    }
+
+    #[test]
+    fn high_fees_go_first() {
+        let mut state=State::new();
+        for sender in 1..4{
+            state.add_account(ico_address(sender),amount(1000));
+        }
+        let mut mempool=Mempool::new();
+        let context=BlockContext{chain_id:mempool.chain_id(),time:0};
+        let a_1=signed_transaction(1,1,1);
+        let a_2=signed_transaction(1,2,50);
+        let b_1=signed_transaction(2,1,10);
+        let c_1=signed_transaction(3,1,5);
+        let c_2=signed_transaction(3,2,20);
+        for transaction in [&a_1,&a_2,&b_1,&c_1,&c_2].iter(){
+            mempool.insert((*transaction).clone(),&state).unwrap();
+        }
+        // sender 1's high fee is stuck behind its low fee
+        let hashes=|transactions:Vec<LedgerTransaction>| transactions.iter().map(|transaction| transaction.hash()).collect::<Vec<H256>>();
+        assert_eq!(hashes(select_transactions(&mempool,&state,&context,3,usize::MAX)), hashes(vec![b_1.clone(),c_1.clone(),c_2.clone()]));
+        assert_eq!(hashes(select_transactions(&mempool,&state,&context,10,usize::MAX)), hashes(vec![b_1.clone(),c_1.clone(),c_2,a_1,a_2]));
+        let size=b_1.size();
+        assert_eq!(hashes(select_transactions(&mempool,&state,&context,10,2*size)), hashes(vec![b_1,c_1]));
+    }
+
+    #[test]
+    fn template_chains_one_sender() {
+        let mut state=State::new();
+        state.add_account(ico_address(1),amount(1000));
+        let mut mempool=Mempool::new();
+        let context=BlockContext{chain_id:mempool.chain_id(),time:0};
+        // sent back to back, and received out of order
+        let chain:Vec<LedgerTransaction>=(1..5).map(|nonce| signed_transaction(1,nonce,1)).collect();
+        for transaction in chain.iter().rev(){
+            mempool.insert(transaction.clone(),&state).unwrap();
+        }
+        let selected=select_transactions(&mempool,&state,&context,3,usize::MAX);
+        assert_eq!(selected.iter().map(|transaction| transaction.as_account().unwrap().trans_raw.nonce).collect::<Vec<usize>>(), vec![1,2,3]);
+    }
+}
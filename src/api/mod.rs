@@ -2,11 +2,17 @@ pub mod miner;
 pub mod address;
 use serde::Serialize;
 use miner::Handle as MinerHandle;
+use crate::basic::mempool::Mempool;
+use crate::blockchain::blockchain::Blockchain;
+use crate::crypto::hash::Hashable;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
 
-use log::info;
+use log::{error, info};
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use tiny_http::Header;
 use tiny_http::Response;
@@ -17,14 +23,35 @@ pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
     network: NetworkServerHandle,
+    mempool: Arc<Mutex<Mempool>>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    /// Where the mempool is saved on shutdown, if anywhere
+    mempool_path: Option<PathBuf>,
 }
 
+/// The most transactions a memo search returns
+const MAX_SEARCH_RESULTS: usize = 100;
+
 #[derive(Serialize)]
 struct ApiResponse {
     success: bool,
     message: String,
 }
 
+#[derive(Serialize)]
+struct ApiData<T> {
+    success: bool,
+    data: T,
+}
+
+/// A transaction found by its memo, all in hex
+#[derive(Serialize)]
+struct MemoMatch {
+    txid: String,
+    block: String,
+    memo: String,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -38,22 +65,44 @@ macro_rules! respond_result {
     }};
 }
 
+macro_rules! respond_data {
+    ( $req:expr, $data:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let payload = ApiData {
+            success: true,
+            data: $data,
+        };
+        let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
 impl Server {
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         network: &NetworkServerHandle,
+        mempool: &Arc<Mutex<Mempool>>,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool_path: Option<PathBuf>,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
             handle,
             miner: miner.clone(),
             network: network.clone(),
+            mempool: Arc::clone(mempool),
+            blockchain: Arc::clone(blockchain),
+            mempool_path,
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let network = server.network.clone();
+                let mempool = Arc::clone(&server.mempool);
+                let blockchain = Arc::clone(&server.blockchain);
+                let mempool_path = server.mempool_path.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -97,6 +146,56 @@ impl Server {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/transaction/search" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let prefix = match params.get("memo").map(hex::decode) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing memo: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing memo");
+                                    return;
+                                }
+                            };
+                            let limit = match params.get("limit").map(|v| v.parse::<usize>()) {
+                                Some(Ok(v)) => v.min(MAX_SEARCH_RESULTS),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing limit: {}", e));
+                                    return;
+                                }
+                                None => MAX_SEARCH_RESULTS,
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let matches: Vec<MemoMatch> = blockchain
+                                .transactions_by_memo(&prefix, limit)
+                                .into_iter()
+                                .map(|(transaction, block)| MemoMatch {
+                                    txid: transaction.hash().to_string(),
+                                    block: block.to_string(),
+                                    memo: hex::encode(transaction.memo()),
+                                })
+                                .collect();
+                            drop(blockchain);
+                            respond_data!(req, matches);
+                        }
+                        "/node/shutdown" => {
+                            // the lock is kept until the process is gone, so nothing is lost after the save
+                            let mempool = mempool.lock().unwrap();
+                            if let Some(path) = mempool_path {
+                                if let Err(e) = mempool.save(&path) {
+                                    error!("Error saving the mempool to {}: {}", path.display(), e);
+                                    respond_result!(req, false, format!("error saving the mempool: {}", e));
+                                    return;
+                                }
+                                info!("Saved {} transactions to {}", mempool.get_size(), path.display());
+                            }
+                            respond_result!(req, true, "ok");
+                            info!("Shutting down");
+                            process::exit(0);
+                        }
                         _ => {
                             let content_type =
                                 "Content-Type: application/json".parse::<Header>().unwrap();
@@ -0,0 +1,92 @@
+//! Amounts of coins.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How many decimal places a coin is divided into
+pub const DECIMALS: u32 = 8;
+/// One coin, in base units
+pub const COIN: u64 = 100_000_000;
+/// The most coins that can ever exist, in base units
+pub const MAX_SUPPLY: Amount = Amount(21_000_000 * COIN);
+
+/// An amount of coins, counted in base units.
+///
+/// The arithmetic is checked: a result below zero or above `MAX_SUPPLY` is `None`, so an amount
+/// computed from valid amounts is always within the supply.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_base_units(units: u64) -> Self {
+        Amount(units)
+    }
+
+    pub const fn base_units(self) -> u64 {
+        self.0
+    }
+
+    /// Whether the amount is at most the total supply
+    pub fn is_valid(self) -> bool {
+        self <= MAX_SUPPLY
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        let sum = Amount(self.0.checked_add(other.0)?);
+        if sum.is_valid() {
+            Some(sum)
+        } else {
+            None
+        }
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// The sum, or `MAX_SUPPLY` if it would be above it
+    pub fn saturating_add(self, other: Amount) -> Amount {
+        self.checked_add(other).unwrap_or(MAX_SUPPLY)
+    }
+
+    /// The sum of `amounts`, or `None` if any partial sum is out of range
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts.into_iter().try_fold(Amount::ZERO, Amount::checked_add)
+    }
+}
+
+impl fmt::Display for Amount {
+    /// In coins, with all the decimal places
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:0width$}", self.0 / COIN, self.0 % COIN, width = DECIMALS as usize)
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic_stays_within_supply() {
+        let one = Amount::from_base_units(1);
+        assert_eq!(one.checked_add(one), Some(Amount::from_base_units(2)));
+        assert_eq!(MAX_SUPPLY.checked_add(Amount::ZERO), Some(MAX_SUPPLY));
+        assert_eq!(MAX_SUPPLY.checked_add(one), None);
+        assert_eq!(Amount::from_base_units(u64::MAX).checked_add(one), None);
+        assert_eq!(Amount::from_base_units(u64::MAX).checked_add(Amount::ZERO), None);
+        assert_eq!(Amount::ZERO.checked_sub(one), None);
+        assert_eq!(MAX_SUPPLY.saturating_add(one), MAX_SUPPLY);
+        assert_eq!(Amount::checked_sum(vec![one; 3]), Some(Amount::from_base_units(3)));
+        assert_eq!(Amount::checked_sum(vec![MAX_SUPPLY, one]), None);
+    }
+
+    #[test]
+    fn displays_in_coins() {
+        assert_eq!(Amount::ZERO.to_string(), "0.00000000");
+        assert_eq!(Amount::from_base_units(50).to_string(), "0.00000050");
+        assert_eq!(Amount::from_base_units(COIN + 1).to_string(), "1.00000001");
+        assert_eq!(MAX_SUPPLY.to_string(), "21000000.00000000");
+    }
+}
@@ -5,8 +5,11 @@
 
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::{H256, Hashable};
-use crate::crypto::key_pair::random;
-use crate::transaction::transaction::SignedTransaction as Transaction;
+use crate::crypto::merkle::MerkleTree;
+use crate::api::address::H160 as Address;
+use crate::basic::amount::Amount;
+use crate::blockchain::config::ChainConfig;
+use crate::transaction::transaction::LedgerTransaction as Transaction;
 //use std::collections::hash_map::RawEntryMut;
 use std::time::{SystemTime};
 use ring::{digest};
@@ -20,9 +23,19 @@ pub struct Header {
     pub merkle_root: H256,
 }
 
+/// The reward paid to the miner of a block: the subsidy plus the fees of the block's transactions.
+/// The height makes the coinbase of every block distinct.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Coinbase {
+    pub height: u64,
+    pub miner: Address,
+    pub value: Amount,
+}
+
 /// Transactions contained in a block,the transaction is Signed transaction
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Content {
+    pub coinbase: Option<Coinbase>,
     pub transactions: Vec<Transaction>,
 }
 
@@ -48,23 +61,28 @@ fn random_difficulty() -> H256 {
     let b = <H256>::from(random);
     b
 }
-fn default_difficulty()-> [u8; 32]{
+pub fn default_difficulty()-> [u8; 32]{
     let mut difficulty = [0u8; 32];
     difficulty[0] = 1;
     difficulty
 }
 impl Block {
-    /// Construct the (totally deterministic) genesis block
+    /// Construct the (totally deterministic) genesis block of the default chain config
     pub fn genesis() -> Block {
-        let transactions: Vec<Transaction> = vec![];
+        Self::genesis_with(ChainConfig::default().hash(), vec![])
+    }
+    /// The genesis block of the network `network` whose ledger starts out with `transactions`.
+    /// A genesis has no parent, so its parent field holds the network instead; that way every
+    /// network has a genesis hash, and so a chain id, of its own.
+    pub fn genesis_with(network: H256, transactions: Vec<Transaction>) -> Block {
+        let content = Content { coinbase: None, transactions };
         let header = Header {
-            parent: Default::default(),
+            parent: network,
             nonce: 0,
             difficulty:default_difficulty().into(),
             timestamp: 0,
-            merkle_root:Default::default()
+            merkle_root:content.merkle_root()
         };
-        let content = Content { transactions };
         Block { header, content }
     }
     pub fn size(&self)->usize{
@@ -79,6 +97,38 @@ impl Block {
     }
 }
 
+impl Content {
+    /// The merkle root over the coinbase and transaction hashes, or the zero hash for an empty block.
+    pub fn merkle_root(&self) -> H256 {
+        let leaves: Vec<H256> = self.coinbase.iter().map(|coinbase| coinbase.hash())
+            .chain(self.transactions.iter().map(|transaction| transaction.hash()))
+            .collect();
+        if leaves.is_empty() {
+            return H256::default();
+        }
+        MerkleTree::new(&leaves).root()
+    }
+
+    /// The fees of all transactions, which the coinbase may claim on top of the subsidy.
+    /// A sum above the total supply is capped at it; such a block cannot apply anyway.
+    pub fn total_fees(&self) -> Amount {
+        self.transactions.iter().fold(Amount::ZERO, |fees, transaction| fees.saturating_add(transaction.fee()))
+    }
+}
+
+impl Header {
+    /// The expected number of hashes needed to find a header meeting this header's target,
+    /// i.e. `2^256 / (difficulty + 1)`.
+    pub fn work(&self) -> H256 {
+        let one = H256::from(1u64);
+        match self.difficulty.checked_add(&one) {
+            // 2^256 / (t + 1) == (2^256 - t - 1) / (t + 1) + 1, which fits in 256 bits
+            Some(divisor) => (!self.difficulty).checked_div(&divisor).unwrap().checked_add(&one).unwrap(),
+            None => one,
+        }
+    }
+}
+
 impl Hashable for Header {
     /// Hash the block header using SHA256.
     fn hash(&self) -> H256 {
@@ -87,6 +137,13 @@ impl Hashable for Header {
     }
 }
 
+impl Hashable for Coinbase {
+    fn hash(&self) -> H256 {
+        let bytes = bincode::serialize(&self).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &bytes).into()
+    }
+}
+
 impl Hashable for Block {
     /// Hash only the block header.
     fn hash(&self) -> H256 {
@@ -106,11 +163,15 @@ impl Hashable for Block {
 pub mod test {
     use super::*;
     use crate::crypto::hash::H256;
-    use crate::crypto::merkle::MerkleTree;
     
     pub fn generate_random_block(parent: &H256) -> Block {
-        let transactions: Vec<Transaction> = vec![Default::default()];
-        let root = MerkleTree::new(&transactions).root();
+        generate_block(parent, vec![])
+    }
+
+    /// A block with the given transactions. Like `generate_random_block`, it has no real proof of work.
+    pub fn generate_block(parent: &H256, transactions: Vec<Transaction>) -> Block {
+        let content = Content { coinbase: None, transactions };
+        let root = content.merkle_root();
         let header = Header {
             parent: *parent,
             nonce: rand::random(),
@@ -118,7 +179,6 @@ pub mod test {
             timestamp: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("Time went backwards").as_millis(),
             merkle_root: root,
         };
-        let content = Content { transactions };
         Block { header, content }
     }
 }
\ No newline at end of file
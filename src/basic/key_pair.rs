@@ -1,5 +1,8 @@
 use ring::rand;
 use ring::signature::Ed25519KeyPair;
+use std::fs;
+use std::io;
+use std::path::Path;
 
 pub fn random() -> Ed25519KeyPair {
     let rng = rand::SystemRandom::new();
@@ -8,4 +11,18 @@ pub fn random() -> Ed25519KeyPair {
 }
 pub fn from_seed(seed: [u8; 32]) -> Ed25519KeyPair {
     Ed25519KeyPair::from_seed_unchecked(&seed).unwrap()
-}
\ No newline at end of file
+}
+/// Load the key pair saved at `path`, or generate one and save it there if there is none yet
+pub fn load_or_create(path: &Path) -> io::Result<Ed25519KeyPair> {
+    let pkcs8_bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let rng = rand::SystemRandom::new();
+            let bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap().as_ref().to_vec();
+            fs::write(path, &bytes)?;
+            bytes
+        }
+        Err(e) => return Err(e),
+    };
+    Ed25519KeyPair::from_pkcs8(&pkcs8_bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
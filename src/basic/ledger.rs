@@ -0,0 +1,132 @@
+//! The ledger models a chain can keep, and what validation, the mempool and the miner need
+//! from either of them.
+
+use crate::api::address::H160 as Address;
+use crate::basic::amount::Amount;
+use crate::basic::state::StateUndo;
+use crate::crypto::hash::H256;
+use crate::transaction::htlc::Escrow;
+use crate::transaction::token::Token;
+use crate::transaction::transaction::{LedgerTransaction, TransactionError};
+use crate::transaction::utxo::{OutPoint, TxOutput};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// How a chain keeps track of who owns what. It is chosen at genesis and never changes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedgerModel {
+    /// Accounts with a nonce and a balance, see `State`
+    Account,
+    /// Unspent transaction outputs, see `UtxoSet`
+    Utxo,
+}
+
+impl std::str::FromStr for LedgerModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "account" => Ok(LedgerModel::Account),
+            "utxo" => Ok(LedgerModel::Utxo),
+            _ => Err(format!("unknown ledger model {}, expected account or utxo", s)),
+        }
+    }
+}
+
+/// What a ledger cannot know about the block a transaction goes into, but scripts may check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BlockContext {
+    /// The chain the block is on, which signatures commit to
+    pub chain_id: H256,
+    /// The block's timestamp
+    pub time: u128,
+}
+
+/// The state of a ledger after some block.
+///
+/// Every change returns a `StateUndo` that `revert` takes back, latest first. Block rewards are
+/// held back until they mature, the same way in both models.
+pub trait Ledger: Send + fmt::Display {
+    fn model(&self) -> LedgerModel;
+
+    /// The height of the block this is the state after
+    fn height(&self) -> u64;
+
+    /// Apply a transaction whose signatures have already been checked. It goes into the block
+    /// after this state, described by `context`, so it must be valid at that height.
+    fn apply_transaction(
+        &mut self,
+        transaction: &LedgerTransaction,
+        context: &BlockContext,
+    ) -> Result<StateUndo, TransactionError>;
+
+    /// Hold back the reward of the block at `height` until it matures
+    fn add_reward(&mut self, height: u64, miner: Address, value: Amount) -> StateUndo;
+
+    /// Credit the rewards of the blocks up to `height` to their miners.
+    /// Nothing changes and `None` is returned if a balance would go above the total supply.
+    fn mature_rewards(&mut self, height: u64) -> Option<StateUndo>;
+
+    /// Move on to the state after the block at `height`
+    fn set_height(&mut self, height: u64) -> StateUndo;
+
+    /// Undo changes, which must be the latest ones applied to this state
+    fn revert(&mut self, undo: StateUndo);
+
+    /// Rewards not credited yet, by the height of their block
+    fn immature_rewards(&self) -> &BTreeMap<u64, (Address, Amount)>;
+
+    /// The nonce and balance of an account; always `None` in the UTXO model
+    fn account(&self, address: &Address) -> Option<(usize, Amount)>;
+
+    /// An escrow locked by a hash time-locked transfer; always `None` in the UTXO model
+    fn escrow(&self, id: &H256) -> Option<&Escrow>;
+
+    /// A token created by a `TokenCreate` transaction; always `None` in the UTXO model
+    fn token(&self, id: &H256) -> Option<&Token>;
+
+    /// The units of a token an address holds; always 0 in the UTXO model
+    fn token_balance(&self, token: &H256, address: &Address) -> u64;
+
+    /// An output that can be spent; always `None` in the account model
+    fn unspent_output(&self, outpoint: &OutPoint) -> Option<&TxOutput>;
+
+    /// The outputs `owner` can spend, in outpoint order; always empty in the account model
+    fn outputs_of(&self, owner: &Address) -> Vec<(OutPoint, TxOutput)>;
+
+    fn box_clone(&self) -> Box<dyn Ledger>;
+
+    /// Apply transactions in order, all or nothing
+    fn apply_transactions(
+        &mut self,
+        transactions: &[LedgerTransaction],
+        context: &BlockContext,
+    ) -> Result<StateUndo, (usize, TransactionError)> {
+        let mut undo = StateUndo::default();
+        for (index, transaction) in transactions.iter().enumerate() {
+            match self.apply_transaction(transaction, context) {
+                Ok(transaction_undo) => undo.append(transaction_undo),
+                Err(error) => {
+                    self.revert(undo);
+                    return Err((index, error));
+                }
+            }
+        }
+        Ok(undo)
+    }
+}
+
+impl Clone for Box<dyn Ledger> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// Reject a transaction that is only valid up to a height below `height`
+pub fn check_expiry(transaction: &LedgerTransaction, height: u64) -> Result<(), TransactionError> {
+    match transaction.valid_until_height() {
+        Some(valid_until) if valid_until < height => Err(TransactionError::Expired { valid_until, height }),
+        _ => Ok(()),
+    }
+}
@@ -1,59 +1,599 @@
-use crate::transaction::transaction::SignedTransaction;
-use std::collections::HashMap;
+use crate::transaction::transaction::{LedgerTransaction, SignedTransaction, Transaction, TransactionError, TransactionKind};
+use crate::transaction::utxo::{OutPoint, UtxoTransaction};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use crate::crypto::hash::{H256, Hashable};
-use crate::basic::state::State;
+use crate::api::address::H160 as Address;
+use crate::basic::ledger::{check_expiry, BlockContext, Ledger};
+use crate::basic::state::check_rules;
+use crate::basic::utxo_set::check_spends;
+use crate::basic::block::Block;
+use crate::basic::amount::Amount;
+use crate::blockchain::blockchain::{Blockchain, ReorgEvent};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 pub static TERMINATE_THREAD:i8=0;
+/// What a saved mempool file starts with, followed by the format version
+const FILE_MAGIC:&[u8;4]=b"MPOL";
+/// Bumped whenever the saved format changes, so an old file is not misread
+pub const FILE_VERSION:u32=7;
+/// Default cap on the number of transactions in the mempool
+pub const DEFAULT_MAX_TRANSACTIONS:usize=10_000;
+/// Default cap on the serialized size of all transactions in the mempool, in bytes
+pub const DEFAULT_MAX_BYTES:usize=5_000_000;
+/// How far above the fee rate of an evicted transaction the minimum fee rate goes
+pub const INCREMENTAL_FEE_RATE:u64=10;
+/// How far beyond a sender's nonce at the tip a transaction may be queued
+pub const MAX_NONCE_GAP:usize=16;
+/// Default time a transaction may wait in the mempool, in milliseconds
+pub const DEFAULT_LIFETIME_MS:u128=3*60*60*1000;
+/// Default share by which a replacement has to raise the fee of the transaction it replaces, in percent
+pub const DEFAULT_REPLACEMENT_BUMP_PERCENT:usize=10;
+/// Why a transaction was not let into the mempool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError{
+    AlreadyKnown,
+    /// The transaction can never apply on top of the tip.
+    Invalid(TransactionError),
+    /// The nonce is too far ahead of the sender's nonce at the tip.
+    NonceTooHigh{nonce:usize,max:usize},
+    /// Another transaction of the sender uses the nonce and this one does not pay enough more
+    /// to replace it.
+    ReplacementUnderpriced{fee:Amount,required:Amount},
+    /// The fee rate is below the minimum, which rises while the mempool is full.
+    FeeTooLow{fee_rate:u64,minimum:u64},
+    /// The mempool is full of transactions paying at least as much.
+    Full,
+    /// An input is spent by another transaction in the mempool.
+    Conflict{hash:H256},
+}
+impl std::fmt::Display for MempoolError{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self{
+            MempoolError::AlreadyKnown=>write!(f,"already in the mempool"),
+            MempoolError::Invalid(error)=>write!(f,"{}",error),
+            MempoolError::NonceTooHigh{nonce,max}=>write!(f,"nonce {} is beyond {}",nonce,max),
+            MempoolError::ReplacementUnderpriced{fee,required}=>write!(f,"fee {} is too low to replace a transaction, {} is required",fee,required),
+            MempoolError::FeeTooLow{fee_rate,minimum}=>write!(f,"fee rate {} is below the minimum {}",fee_rate,minimum),
+            MempoolError::Full=>write!(f,"mempool is full"),
+            MempoolError::Conflict{hash}=>write!(f,"spends an output that {} spends",hash),
+        }
+    }
+}
+struct MempoolEntry{
+    transaction:LedgerTransaction,
+    size:usize,
+    fee_rate:u64,
+    added:u128,//when it entered the mempool, in milliseconds since the epoch
+}
+/// The transactions of one sender by nonce. `pending` ones follow the sender's nonce at the tip
+/// without a gap and can be mined in order; `queued` ones wait for a gap before them to close.
+#[derive(Default)]
+struct SenderQueue{
+    nonce:usize,//the sender's nonce at the tip
+    pending:BTreeMap<usize,H256>,
+    queued:BTreeMap<usize,H256>,
+}
+impl SenderQueue{
+    /// The nonce that would extend the pending transactions
+    fn next_nonce(&self)->usize{
+        self.pending.keys().next_back().map_or(self.nonce+1,|nonce| nonce+1)
+    }
+    /// The transactions with another nonce than `nonce`, pending or queued
+    fn others(&self,nonce:usize)->impl Iterator<Item=&H256>{
+        self.pending.iter().chain(self.queued.iter()).filter(move |(other,_)| **other!=nonce).map(|(_,hash)| hash)
+    }
+    fn get(&self,nonce:usize)->Option<H256>{
+        self.pending.get(&nonce).or_else(|| self.queued.get(&nonce)).copied()
+    }
+    fn add(&mut self,nonce:usize,hash:H256){
+        if nonce==self.next_nonce(){
+            self.pending.insert(nonce,hash);
+            self.promote();
+        }
+        else{
+            self.queued.insert(nonce,hash);
+        }
+    }
+    /// Move the queued transactions whose gap has closed to the pending ones
+    fn promote(&mut self){
+        while let Some(hash)=self.queued.remove(&self.next_nonce()){
+            let nonce=self.next_nonce();
+            self.pending.insert(nonce,hash);
+        }
+    }
+    /// Remove a nonce; the pending transactions after it have to wait again
+    fn remove(&mut self,nonce:usize){
+        if self.pending.remove(&nonce).is_some(){
+            let mut later=self.pending.split_off(&nonce);
+            self.queued.append(&mut later);
+        }
+        else{
+            self.queued.remove(&nonce);
+        }
+    }
+    /// Follow a new nonce at the tip. Returns the transactions whose nonce is used up.
+    fn resync(&mut self,nonce:usize)->Vec<H256>{
+        self.nonce=nonce;
+        let mut all=std::mem::take(&mut self.pending);
+        all.append(&mut self.queued);
+        self.queued=all.split_off(&(nonce+1));
+        self.promote();
+        all.into_values().collect()
+    }
+    fn is_empty(&self)->bool{
+        self.pending.is_empty() && self.queued.is_empty()
+    }
+}
 /// Store all the received valid transactions which have not been included in the blockchain yet.
+///
+/// Transactions are kept per sender, as pending ones that can be mined now and queued ones with
+/// a nonce gap before them. The mempool is capped by count and by serialized size. When it is
+/// full the transactions paying the least per byte are evicted, and the minimum fee rate for new
+/// ones rises above theirs. The minimum halves again with every block that leaves the mempool
+/// less than half full.
+///
+/// A sender has at most one transaction per nonce. Another one with the same nonce replaces it
+/// if it raises the fee by the replacement bump.
+///
+/// On a UTXO chain every transaction has to spend outputs that are unspent at the tip, and the
+/// first transaction to spend an output keeps it; there are no queues or replacements.
+///
+/// Transactions leave after waiting for the lifetime, or once the tip is past their
+/// `valid_until_height`.
 pub struct Mempool {
-    // TODO Optional: you may use other data structures if you wish.
-    pub hash_to_transaction: HashMap<H256, SignedTransaction>,
+    hash_to_transaction: HashMap<H256, MempoolEntry>,
+    senders: HashMap<Address, SenderQueue>,
+    spent: HashMap<OutPoint, H256>,//the outputs that UTXO transactions spend
+    by_fee_rate: BTreeSet<(u64,H256)>,
+    total_bytes: usize,
+    max_count: usize,
+    max_bytes: usize,
+    min_fee_rate: u64,
+    replacement_bump_percent: usize,
+    lifetime_ms: u128,
+    chain_id: H256,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Mempool {
     pub fn new() -> Self {
+        Self::with_limits(DEFAULT_MAX_TRANSACTIONS,DEFAULT_MAX_BYTES)
+    }
+    pub fn with_limits(max_count:usize,max_bytes:usize) -> Self {
         Mempool {
             hash_to_transaction: HashMap::new(),
+            senders: HashMap::new(),
+            spent: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            total_bytes: 0,
+            max_count,
+            max_bytes,
+            min_fee_rate: 0,
+            replacement_bump_percent: DEFAULT_REPLACEMENT_BUMP_PERCENT,
+            lifetime_ms: DEFAULT_LIFETIME_MS,
+            chain_id: Block::genesis().hash(),
         }
     }
+    /// Only accept transactions signed for `chain_id` instead of the chain of the default config
+    pub fn with_chain_id(mut self,chain_id:H256) -> Self {
+        self.chain_id=chain_id;
+        self
+    }
+    pub fn chain_id(&self) -> H256 {
+        self.chain_id
+    }
+    /// Let transactions wait for `lifetime_ms` instead of the default
+    pub fn with_lifetime(mut self,lifetime_ms:u128) -> Self {
+        self.lifetime_ms=lifetime_ms;
+        self
+    }
+    /// Require replacements to raise the fee by `percent` instead of the default
+    pub fn with_replacement_bump(mut self,percent:usize) -> Self {
+        self.replacement_bump_percent=percent;
+        self
+    }
     pub fn get_size(&self) -> usize{
         self.hash_to_transaction.len()
     }
+    /// The number of transactions waiting for an earlier nonce
+    pub fn queued_size(&self) -> usize{
+        self.senders.values().map(|queue| queue.queued.len()).sum()
+    }
+    /// The serialized size of all transactions, in bytes
+    pub fn total_bytes(&self) -> usize{
+        self.total_bytes
+    }
+    /// The fee per 1000 bytes a new transaction has to pay
+    pub fn min_fee_rate(&self) -> u64{
+        self.min_fee_rate
+    }
     /// Get a transaction from the mempool by hash (or `None` if it does not exist)
-    pub fn get_transaction(&self, hash: &H256) -> Option<&SignedTransaction> {
-        self.hash_to_transaction.get(hash)
+    pub fn get_transaction(&self, hash: &H256) -> Option<&LedgerTransaction> {
+        self.hash_to_transaction.get(hash).map(|entry| &entry.transaction)
     }
     pub fn contains_hash(&self,hash:&H256)->bool{
         self.hash_to_transaction.contains_key(hash)
     }
-    /// Insert a transaction into the mempool
-    pub fn insert(&mut self, transaction: SignedTransaction) {
+    /// All transactions, in no particular order
+    pub fn transactions(&self)->impl Iterator<Item=&LedgerTransaction>{
+        self.hash_to_transaction.values().map(|entry| &entry.transaction)
+    }
+    /// The transactions that can be mined now, as chains that have to go in order: the pending
+    /// transactions of every sender in nonce order, and every UTXO transaction on its own
+    pub fn pending(&self)->Vec<Vec<&LedgerTransaction>>{
+        let accounts=self.senders.values()
+            .filter(|queue| !queue.pending.is_empty())
+            .map(|queue| queue.pending.values().map(|hash| &self.hash_to_transaction[hash].transaction).collect());
+        let utxos=self.transactions()
+            .filter(|transaction| transaction.as_account().is_none())
+            .map(|transaction| vec![transaction]);
+        accounts.chain(utxos).collect()
+    }
+    /// The nonce for the sender's next transaction, after the ones pending in the mempool
+    pub fn next_nonce(&self,sender:&Address,state:&dyn Ledger)->usize{
+        match self.senders.get(sender){
+            Some(queue)=>queue.next_nonce(),
+            None=>state.account(sender).map_or(1,|(nonce,_)| nonce+1),
+        }
+    }
+    /// Whether a UTXO transaction in the mempool spends `outpoint`
+    pub fn is_spent(&self,outpoint:&OutPoint)->bool{
+        self.spent.contains_key(outpoint)
+    }
+    /// Insert a transaction into the mempool, evicting cheaper ones if it is full.
+    /// `state` is the tip state; the nonce may be ahead of the sender's, up to `MAX_NONCE_GAP`.
+    /// Returns the transaction it replaced, if any.
+    pub fn insert(&mut self, transaction: LedgerTransaction, state:&dyn Ledger) -> Result<Option<LedgerTransaction>,MempoolError> {
+        let now=SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        self.insert_at(transaction,state,now)
+    }
+    /// Insert a transaction that entered the mempool at `added` (milliseconds since the epoch)
+    pub fn insert_at(&mut self, transaction: LedgerTransaction, state:&dyn Ledger, added:u128) -> Result<Option<LedgerTransaction>,MempoolError> {
         // (Make sure you have implemented the `Hashable` trait for `SignedTransaction`, or there will be an error):
         let hash = transaction.hash();
-        self.hash_to_transaction.insert(hash, transaction);
+        if self.contains_hash(&hash){
+            return Err(MempoolError::AlreadyKnown);
+        }
+        if transaction.model()!=state.model(){
+            return Err(MempoolError::Invalid(TransactionError::WrongLedger));
+        }
+        if !transaction.verify_signatures(&self.chain_id){
+            return Err(MempoolError::Invalid(TransactionError::BadSignature));
+        }
+        transaction.check_memo().map_err(MempoolError::Invalid)?;
+        check_expiry(&transaction,state.height()+1).map_err(MempoolError::Invalid)?;
+        let replaced=match &transaction{
+            LedgerTransaction::Account(transaction)=>self.check_account(transaction,state,added)?,
+            LedgerTransaction::Utxo(transaction)=>{
+                self.check_utxo(transaction,state)?;
+                None
+            }
+        };
+        let size=transaction.size();
+        let fee_rate=transaction.fee_rate();
+        if fee_rate<self.min_fee_rate{
+            return Err(MempoolError::FeeTooLow{fee_rate,minimum:self.min_fee_rate});
+        }
+        // find what has to go before touching anything; the replaced transaction goes anyway
+        let mut evicted=Vec::new();
+        let (mut count,mut bytes)=(self.get_size()+1,self.total_bytes+size);
+        if let Some(replaced)=replaced{
+            count-=1;
+            bytes-=self.hash_to_transaction[&replaced].size;
+        }
+        for (rate,cheap) in self.by_fee_rate.iter().filter(|(_,hash)| Some(*hash)!=replaced){
+            if count<=self.max_count && bytes<=self.max_bytes{
+                break;
+            }
+            if *rate>=fee_rate{
+                return Err(MempoolError::Full);
+            }
+            count-=1;
+            bytes-=self.hash_to_transaction[cheap].size;
+            evicted.push((*rate,*cheap));
+        }
+        if count>self.max_count || bytes>self.max_bytes{
+            return Err(MempoolError::Full);
+        }
+        let replaced=replaced.and_then(|replaced| self.remove(&replaced));
+        for (rate,cheap) in evicted{
+            self.remove(&cheap);
+            self.min_fee_rate=self.min_fee_rate.max(rate+INCREMENTAL_FEE_RATE);
+        }
+        match &transaction{
+            LedgerTransaction::Account(transaction)=>{
+                let raw=&transaction.trans_raw;
+                let nonce=state.account(&raw.sender).map_or(0,|(nonce,_)| nonce);
+                self.senders.entry(raw.sender).or_insert_with(|| SenderQueue{nonce,..SenderQueue::default()}).add(raw.nonce,hash);
+            }
+            LedgerTransaction::Utxo(transaction)=>{
+                for input in transaction.inputs.iter(){
+                    self.spent.insert(input.previous,hash);
+                }
+            }
+        }
+        self.by_fee_rate.insert((fee_rate,hash));
+        self.total_bytes+=size;
+        self.hash_to_transaction.insert(hash, MempoolEntry{transaction,size,fee_rate,added});
+        Ok(replaced)
+    }
+    /// Check an account transaction against the tip state and the sender's other transactions.
+    /// The balance has to cover it together with the sender's transactions of other nonces, so
+    /// they can all be mined, and its kind has to be able to apply on the tip state, see `check_rules`.
+    /// A script runs as if the transaction went into the next block at `now`.
+    /// Returns the transaction with the same nonce that it would replace.
+    fn check_account(&mut self,transaction:&SignedTransaction,state:&dyn Ledger,now:u128)->Result<Option<H256>,MempoolError>{
+        if !transaction.signed_by_sender(){
+            return Err(MempoolError::Invalid(TransactionError::NotOwner));
+        }
+        let raw=&transaction.trans_raw;
+        let (nonce,balance)=state.account(&raw.sender).ok_or(MempoolError::Invalid(TransactionError::UnknownSender))?;
+        if raw.nonce<=nonce{
+            return Err(MempoolError::Invalid(TransactionError::BadNonce{expected:nonce+1,got:raw.nonce}));
+        }
+        if raw.nonce>nonce+MAX_NONCE_GAP{
+            return Err(MempoolError::NonceTooHigh{nonce:raw.nonce,max:nonce+MAX_NONCE_GAP});
+        }
+        let context=BlockContext{chain_id:self.chain_id,time:now};
+        let spent_tokens=match &raw.kind{
+            TransactionKind::TokenTransfer{token,..}=>self.other_token_spends(&raw.sender,raw.nonce,token),
+            _=>0,
+        };
+        check_rules(state,transaction,state.height()+1,&context,spent_tokens).map_err(MempoolError::Invalid)?;
+        self.sync_sender(&raw.sender,state);
+        let cost=raw.cost().ok_or(MempoolError::Invalid(TransactionError::Overflow))?;
+        let balance=balance.checked_sub(self.other_cost(&raw.sender,raw.nonce)).unwrap_or(Amount::ZERO);
+        if cost>balance{
+            return Err(MempoolError::Invalid(TransactionError::InsufficientBalance{balance,cost}));
+        }
+        let replaced=self.senders.get(&raw.sender).and_then(|queue| queue.get(raw.nonce));
+        if let Some(replaced)=replaced{
+            let old_fee=self.hash_to_transaction[&replaced].transaction.fee();
+            let bump=(old_fee.base_units() as u128*self.replacement_bump_percent as u128/100).max(1);
+            let required=old_fee.saturating_add(Amount::from_base_units(bump.min(u64::MAX as u128) as u64));
+            if raw.fee<required{
+                return Err(MempoolError::ReplacementUnderpriced{fee:raw.fee,required});
+            }
+        }
+        Ok(replaced)
+    }
+    /// The sender's transactions with another nonce than `nonce`
+    fn others_of<'a>(&'a self,sender:&Address,nonce:usize)->impl Iterator<Item=&'a Transaction>{
+        self.senders.get(sender).into_iter()
+            .flat_map(move |queue| queue.others(nonce))
+            .filter_map(move |hash| self.hash_to_transaction[hash].transaction.as_account())
+            .map(|transaction| &transaction.trans_raw)
+    }
+    /// What the sender's transactions with another nonce than `nonce` cost together
+    fn other_cost(&self,sender:&Address,nonce:usize)->Amount{
+        self.others_of(sender,nonce)
+            .filter_map(|raw| raw.cost())
+            .fold(Amount::ZERO,|total,cost| total.saturating_add(cost))
+    }
+    /// The units of `token` the sender's transactions with another nonce than `nonce` send together
+    fn other_token_spends(&self,sender:&Address,nonce:usize,token:&H256)->u64{
+        self.others_of(sender,nonce)
+            .filter_map(|raw| match &raw.kind{
+                TransactionKind::TokenTransfer{token:other,amount} if other==token=>Some(*amount),
+                _=>None,
+            })
+            .fold(0,|total,amount| total.saturating_add(amount))
+    }
+    /// Check a UTXO transaction against the tip state. None of its inputs may be spent by
+    /// another transaction in the mempool.
+    fn check_utxo(&self,transaction:&UtxoTransaction,state:&dyn Ledger)->Result<(),MempoolError>{
+        if let Some(other)=transaction.inputs.iter().find_map(|input| self.spent.get(&input.previous)){
+            return Err(MempoolError::Conflict{hash:*other});
+        }
+        check_spends(state,transaction).map_err(MempoolError::Invalid)
+    }
+    fn remove(&mut self,hash:&H256)->Option<LedgerTransaction>{
+        let entry=self.hash_to_transaction.remove(hash)?;
+        self.by_fee_rate.remove(&(entry.fee_rate,*hash));
+        self.total_bytes-=entry.size;
+        match &entry.transaction{
+            LedgerTransaction::Account(transaction)=>{
+                let raw=&transaction.trans_raw;
+                if let Some(queue)=self.senders.get_mut(&raw.sender){
+                    queue.remove(raw.nonce);
+                    if queue.is_empty(){
+                        self.senders.remove(&raw.sender);
+                    }
+                }
+            }
+            LedgerTransaction::Utxo(transaction)=>{
+                for input in transaction.inputs.iter(){
+                    self.spent.remove(&input.previous);
+                }
+            }
+        }
+        Some(entry.transaction)
+    }
+    /// Bring a sender's queue in line with its nonce in `state`, dropping used up nonces
+    fn sync_sender(&mut self,sender:&Address,state:&dyn Ledger){
+        let nonce=match state.account(sender){
+            Some((nonce,_))=>nonce,
+            None=>return,
+        };
+        let stale=match self.senders.get_mut(sender){
+            Some(queue) if queue.nonce!=nonce=>queue.resync(nonce),
+            _=>return,
+        };
+        for hash in stale.iter(){
+            self.remove(hash);
+        }
+    }
+    /// Drop the sender's transactions that can no longer apply on `state`, going in nonce order:
+    /// the ones that fail `check_rules` in the next block under `context`, and the ones the
+    /// balance no longer covers after the transactions before them
+    fn check_sender(&mut self,sender:&Address,state:&dyn Ledger,context:&BlockContext){
+        let (mut available,hashes)=match (state.account(sender),self.senders.get(sender)){
+            (Some((_,balance)),Some(queue))=>(balance,queue.pending.values().chain(queue.queued.values()).copied().collect::<Vec<H256>>()),
+            _=>return,
+        };
+        let mut spent_tokens:HashMap<H256,u64>=HashMap::new();
+        let mut invalid=Vec::new();
+        for hash in hashes{
+            let transaction=match self.hash_to_transaction[&hash].transaction.as_account(){
+                Some(transaction)=>transaction,
+                None=>continue,
+            };
+            let raw=&transaction.trans_raw;
+            let spent=match &raw.kind{
+                TransactionKind::TokenTransfer{token,..}=>spent_tokens.get(token).copied().unwrap_or(0),
+                _=>0,
+            };
+            if check_rules(state,transaction,state.height()+1,context,spent).is_err(){
+                invalid.push(hash);
+                continue;
+            }
+            match raw.cost().and_then(|cost| available.checked_sub(cost)){
+                Some(left)=>available=left,
+                None=>{
+                    invalid.push(hash);
+                    continue;
+                }
+            }
+            if let TransactionKind::TokenTransfer{token,amount}=&raw.kind{
+                let total=spent_tokens.entry(*token).or_insert(0);
+                *total=total.saturating_add(*amount);
+            }
+        }
+        for hash in invalid.iter(){
+            self.remove(hash);
+        }
     }
-    pub fn remove_transaction(&mut self,transaction_vec:Vec<SignedTransaction>){
+    pub fn remove_transaction(&mut self,transaction_vec:Vec<LedgerTransaction>){
         for trans in transaction_vec.iter(){
-            if self.contains_hash(&trans.hash()){
-                self.hash_to_transaction.remove(&trans.hash());
+            self.remove(&trans.hash());
+        }
+    }
+    /// Drop what can no longer apply on `now_state`, the new tip state, and promote or queue
+    /// the rest by the senders' new nonces. A sender's transactions are checked again in nonce
+    /// order, as on admission: the ones whose kind can no longer apply, see `check_rules`, and
+    /// the ones the new balance no longer covers are dropped.
+    pub fn check_valid(&mut self,now_state:&dyn Ledger){
+        let invalid:Vec<H256>=self.hash_to_transaction.iter()
+            .filter(|(_,entry)| {
+                let transaction=&entry.transaction;
+                check_expiry(transaction,now_state.height()+1).is_err() || match transaction{
+                    LedgerTransaction::Account(_)=>false,
+                    LedgerTransaction::Utxo(transaction)=>transaction.inputs.iter().any(|input| now_state.unspent_output(&input.previous).is_none()),
+                }
+            })
+            .map(|(hash,_)| *hash)
+            .collect();
+        for hash in invalid.iter(){
+            self.remove(hash);
+        }
+        let now=SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let context=BlockContext{chain_id:self.chain_id,time:now};
+        let senders:Vec<Address>=self.senders.keys().copied().collect();
+        for sender in senders.iter(){
+            match now_state.account(sender){
+                Some(_)=>{
+                    self.sync_sender(sender,now_state);
+                    self.check_sender(sender,now_state,&context);
+                }
+                None=>{
+                    let queue=&self.senders[sender];
+                    let hashes:Vec<H256>=queue.pending.values().chain(queue.queued.values()).copied().collect();
+                    for hash in hashes.iter(){
+                        self.remove(hash);
+                    }
+                }
             }
         }
     }
-    pub fn check_valid(&mut self,now_state:&State){
-        let mut del_vec:Vec<H256>=Vec::new();
-        for (hash,transaction) in self.hash_to_transaction.iter(){
-            if !transaction.verify_by_state(&now_state){
-                del_vec.push(hash.clone());
+    /// Drop the transactions that have waited for the lifetime at `now` (milliseconds since the epoch)
+    pub fn expire(&mut self,now:u128){
+        let expired:Vec<H256>=self.hash_to_transaction.iter()
+            .filter(|(_,entry)| entry.added+self.lifetime_ms<=now)
+            .map(|(hash,_)| *hash)
+            .collect();
+        for hash in expired.iter(){
+            self.remove(hash);
+        }
+    }
+    /// Follow a change of the tip: transactions of disconnected blocks come back to the pool if
+    /// they are still valid against the new tip state, and those of connected blocks leave it.
+    pub fn handle_reorg(&mut self,event:&ReorgEvent,blockchain:&Blockchain){
+        let mut confirmed:HashSet<H256>=HashSet::new();
+        for hash in event.connected.iter(){
+            for transaction in blockchain.get_block(hash).content.transactions.iter(){
+                confirmed.insert(transaction.hash());
+                self.remove(&transaction.hash());
+            }
+            if self.total_bytes<self.max_bytes/2 && self.get_size()<self.max_count/2{
+                self.min_fee_rate/=2;
+            }
+        }
+        self.expire(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis());
+        self.check_valid(blockchain.tip_state());
+        for hash in event.disconnected.iter().rev(){
+            for transaction in blockchain.get_block(hash).content.transactions.iter(){
+                if !confirmed.contains(&transaction.hash()){
+                    // a transaction that no longer fits is dropped like any other
+                    let _=self.insert(transaction.clone(),blockchain.tip_state());
+                }
             }
-        }   
-        for hash in del_vec.iter(){
-            self.hash_to_transaction.remove(&hash);
         }
     }
+    /// Write every transaction with the time it entered the mempool to `path`, in nonce order
+    /// so each sender's load back in sequence.
+    /// The file is written next to `path` first and then moved over it.
+    pub fn save(&self,path:&Path)->io::Result<()>{
+        let mut transactions:Vec<(u128,&LedgerTransaction)>=self.hash_to_transaction.values().map(|entry| (entry.added,&entry.transaction)).collect();
+        transactions.sort_by_key(|(_,transaction)| transaction.as_account().map_or(0,|transaction| transaction.trans_raw.nonce));
+        let mut bytes=FILE_MAGIC.to_vec();
+        bytes.extend_from_slice(&FILE_VERSION.to_le_bytes());
+        bytes.extend(bincode::serialize(&transactions).map_err(|e| io::Error::other(e.to_string()))?);
+        let tmp_path=path.with_extension("tmp");
+        let mut file=fs::File::create(&tmp_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path,path)
+    }
+    /// Insert the transactions saved in `path`, checking each against `state`, the tip state,
+    /// and drop the ones that have waited for the lifetime by now.
+    /// Returns how many were taken back; a missing file is an empty mempool.
+    pub fn load(&mut self,path:&Path,state:&dyn Ledger)->io::Result<usize>{
+        let bytes=match fs::read(path){
+            Ok(bytes)=>bytes,
+            Err(e) if e.kind()==io::ErrorKind::NotFound=>return Ok(0),
+            Err(e)=>return Err(e),
+        };
+        if bytes.len()<8 || &bytes[..4]!=FILE_MAGIC{
+            return Err(io::Error::new(io::ErrorKind::InvalidData,"not a mempool file"));
+        }
+        let mut version=[0u8;4];
+        version.copy_from_slice(&bytes[4..8]);
+        let version=u32::from_le_bytes(version);
+        if version!=FILE_VERSION{
+            return Err(io::Error::new(io::ErrorKind::InvalidData,format!("mempool file version {}, expected {}",version,FILE_VERSION)));
+        }
+        let transactions:Vec<(u128,LedgerTransaction)>=bincode::deserialize(&bytes[8..])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData,e.to_string()))?;
+        let loaded:Vec<H256>=transactions.into_iter()
+            .filter(|(added,transaction)| self.insert_at(transaction.clone(),state,*added).is_ok())
+            .map(|(_,transaction)| transaction.hash())
+            .collect();
+        // the ones whose lifetime ran out while the node was down go right away
+        self.expire(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis());
+        Ok(loaded.iter().filter(|hash| self.contains_hash(hash)).count())
+    }
     /// Remove a random transaction from the mempool and return it (or `None` if it is empty)
-    pub fn pop(&mut self) -> Option<SignedTransaction> {
+    pub fn pop(&mut self) -> Option<LedgerTransaction> {
         let hash = self.hash_to_transaction.keys().next().cloned();
         if let Some(hash) = hash {
-            self.hash_to_transaction.remove(&hash)
+            self.remove(&hash)
         } else {
             None
         }
@@ -63,4 +603,435 @@ impl Mempool {
     }
         
     // TODO Optional: you may want to add more methods here...
-}
\ No newline at end of file
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::basic::block::test::generate_block;
+    use crate::blockchain::blockchain::{ico_address, ico_key_pair, ico_transaction};
+    use crate::basic::ledger::BlockContext;
+    use crate::basic::state::State;
+    use crate::basic::utxo_set::UtxoSet;
+    use crate::crypto::key_pair;
+    use crate::transaction::htlc::{hashlock, Escrow};
+    use crate::transaction::script::{Op, Script, ScriptError, ScriptWitness};
+    use crate::transaction::token::Token;
+    use crate::transaction::transaction::signing_message;
+    use ring::signature::KeyPair;
+    use crate::transaction::utxo::TxOutput;
+
+    fn amount(units:u64)->Amount{
+        Amount::from_base_units(units)
+    }
+
+    fn signed_transaction(sender:u8,nonce:usize,value:u64)->LedgerTransaction{
+        signed_transaction_with_fee(sender,nonce,value,0)
+    }
+
+    fn signed_transaction_with_fee(sender:u8,nonce:usize,value:u64,fee:u64)->LedgerTransaction{
+        let raw=Transaction{sender:ico_address(sender),receiver:ico_address(9),nonce,value:amount(value),fee:amount(fee),valid_until_height:None,kind:TransactionKind::Transfer,memo:Vec::new()};
+        SignedTransaction::from_raw(raw,&Block::genesis().hash(),&ico_key_pair(sender)).into()
+    }
+
+    fn transaction_of_kind(sender:u8,nonce:usize,kind:TransactionKind)->LedgerTransaction{
+        let raw=Transaction{kind,..signed_transaction(sender,nonce,0).as_account().unwrap().trans_raw.clone()};
+        SignedTransaction::from_raw(raw,&Block::genesis().hash(),&ico_key_pair(sender)).into()
+    }
+
+    /// A state where senders 1 to 8 have no transactions yet and 1000 each
+    fn funded_state()->State{
+        let mut state=State::new();
+        for sender in 1..9{
+            state.add_account(ico_address(sender),amount(1000));
+        }
+        state
+    }
+
+    #[test]
+    fn reorg_returns_transactions() {
+        let mut blockchain=Blockchain::new();
+        let events=blockchain.subscribe();
+        let mut mempool=Mempool::new();
+        let genesis_hash=blockchain.tip();
+        let kept=signed_transaction(1,1,10);
+        let dropped=signed_transaction(2,1,10);
+        let double_spent=signed_transaction(3,1,10);
+        let block_a=generate_block(&genesis_hash,vec![kept.clone(),dropped.clone(),double_spent.clone()]);
+        blockchain.insert(&block_a);
+        mempool.handle_reorg(&events.try_recv().unwrap(),&blockchain);
+        assert!(mempool.is_empty());
+
+        // the other branch confirms `dropped` again and spends sender 3's nonce differently
+        let block_b1=generate_block(&genesis_hash,vec![dropped.clone(),signed_transaction(3,1,20)]);
+        let block_b2=generate_block(&block_b1.hash(),vec![]);
+        blockchain.insert(&block_b1);
+        blockchain.insert(&block_b2);
+        let event=events.try_recv().unwrap();
+        assert_eq!(event.disconnected,vec![block_a.hash()]);
+        mempool.handle_reorg(&event,&blockchain);
+        assert_eq!(mempool.get_size(),1);
+        assert!(mempool.contains_hash(&kept.hash()));
+    }
+
+    #[test]
+    fn full_mempool_evicts_lowest_fee_rate() {
+        let state=funded_state();
+        let cheap=signed_transaction_with_fee(1,1,10,1);
+        let size=cheap.size();
+        let mut mempool=Mempool::with_limits(3,usize::MAX);
+        mempool.insert(cheap.clone(),&state).unwrap();
+        mempool.insert(signed_transaction_with_fee(2,1,10,2),&state).unwrap();
+        mempool.insert(signed_transaction_with_fee(3,1,10,3),&state).unwrap();
+        assert_eq!(mempool.insert(cheap.clone(),&state), Err(MempoolError::AlreadyKnown));
+        assert_eq!(mempool.insert(signed_transaction_with_fee(4,1,10,1),&state), Err(MempoolError::Full));
+        assert_eq!(mempool.get_size(), 3);
+        assert_eq!(mempool.total_bytes(), 3*size);
+
+        // a better paying one takes the place of the cheapest, and the minimum goes up
+        mempool.insert(signed_transaction_with_fee(5,1,10,5),&state).unwrap();
+        assert!(!mempool.contains_hash(&cheap.hash()));
+        assert_eq!(mempool.get_size(), 3);
+        let minimum=cheap.fee_rate()+INCREMENTAL_FEE_RATE;
+        assert_eq!(mempool.min_fee_rate(), minimum);
+        let low=signed_transaction_with_fee(6,1,10,1);
+        assert_eq!(mempool.insert(low.clone(),&state), Err(MempoolError::FeeTooLow{fee_rate:low.fee_rate(),minimum}));
+
+        // the byte cap works the same way
+        let mut mempool=Mempool::with_limits(100,2*size);
+        mempool.insert(signed_transaction_with_fee(1,1,10,3),&state).unwrap();
+        mempool.insert(signed_transaction_with_fee(2,1,10,2),&state).unwrap();
+        mempool.insert(signed_transaction_with_fee(3,1,10,4),&state).unwrap();
+        assert_eq!(mempool.get_size(), 2);
+        assert_eq!(mempool.total_bytes(), 2*size);
+        assert_eq!(mempool.transactions().map(|transaction| transaction.fee()).min(), Some(amount(3)));
+    }
+
+    #[test]
+    fn minimum_fee_decays_with_blocks() {
+        let mut blockchain=Blockchain::new();
+        let events=blockchain.subscribe();
+        let state=funded_state();
+        let mut mempool=Mempool::with_limits(2,usize::MAX);
+        mempool.insert(signed_transaction_with_fee(1,1,10,1),&state).unwrap();
+        let kept=vec![signed_transaction_with_fee(2,1,10,2),signed_transaction_with_fee(3,1,10,3)];
+        for transaction in kept.iter(){
+            mempool.insert(transaction.clone(),&state).unwrap();
+        }
+        let minimum=mempool.min_fee_rate();
+        assert!(minimum>0);
+        // a block that leaves the mempool more than half full does not lower the minimum
+        let block_1=generate_block(&blockchain.tip(),vec![]);
+        blockchain.insert(&block_1);
+        mempool.handle_reorg(&events.try_recv().unwrap(),&blockchain);
+        assert_eq!(mempool.min_fee_rate(), minimum);
+        let block_2=generate_block(&block_1.hash(),kept);
+        blockchain.insert(&block_2);
+        mempool.handle_reorg(&events.try_recv().unwrap(),&blockchain);
+        assert_eq!(mempool.get_size(), 0);
+        assert_eq!(mempool.min_fee_rate(), minimum/2);
+    }
+
+    #[test]
+    fn queued_transactions_are_promoted() {
+        let state=funded_state();
+        let mut mempool=Mempool::new();
+        let sender=ico_address(1);
+        let third=signed_transaction(1,3,10);
+        mempool.insert(third.clone(),&state).unwrap();
+        assert_eq!(mempool.queued_size(), 1);
+        assert!(mempool.pending().is_empty());
+        assert_eq!(mempool.next_nonce(&sender,&state), 1);
+        mempool.insert(signed_transaction(1,1,10),&state).unwrap();
+        assert_eq!(mempool.queued_size(), 1);
+        assert_eq!(mempool.next_nonce(&sender,&state), 2);
+        // the gap closes and the third one becomes pending too
+        mempool.insert(signed_transaction(1,2,10),&state).unwrap();
+        assert_eq!(mempool.queued_size(), 0);
+        let nonces:Vec<usize>=mempool.pending()[0].iter().map(|transaction| transaction.as_account().unwrap().trans_raw.nonce).collect();
+        assert_eq!(nonces, vec![1,2,3]);
+        assert_eq!(mempool.next_nonce(&sender,&state), 4);
+
+        assert_eq!(mempool.insert(signed_transaction(1,2,20),&state), Err(MempoolError::ReplacementUnderpriced{fee:amount(0),required:amount(1)}));
+        assert_eq!(mempool.insert(signed_transaction(1,0,10),&state),
+            Err(MempoolError::Invalid(TransactionError::BadNonce{expected:1,got:0})));
+        assert_eq!(mempool.insert(signed_transaction(1,1+MAX_NONCE_GAP,10),&state),
+            Err(MempoolError::NonceTooHigh{nonce:1+MAX_NONCE_GAP,max:MAX_NONCE_GAP}));
+        assert_eq!(mempool.insert(signed_transaction(9,1,10),&state),
+            Err(MempoolError::Invalid(TransactionError::UnknownSender)));
+
+        // losing a pending transaction puts the later ones back in the queue
+        let second=mempool.pending()[0][1].clone();
+        mempool.remove_transaction(vec![second]);
+        assert_eq!(mempool.queued_size(), 1);
+        assert_eq!(mempool.pending()[0].len(), 1);
+
+        // once the tip has used nonces 1 and 2, the third one is pending again
+        let mut next_state=state.clone();
+        next_state.add_an_account(sender,2,amount(900));
+        mempool.check_valid(&next_state);
+        assert_eq!(mempool.get_size(), 1);
+        assert_eq!(mempool.pending()[0][0].hash(), third.hash());
+    }
+
+    #[test]
+    fn sender_transactions_share_the_balance() {
+        let state=funded_state();
+        let mut mempool=Mempool::new();
+        mempool.insert(signed_transaction(1,1,600),&state).unwrap();
+        assert_eq!(mempool.insert(signed_transaction(1,2,500),&state),
+            Err(MempoolError::Invalid(TransactionError::InsufficientBalance{balance:amount(400),cost:amount(500)})));
+        // a queued transaction counts too, so the gap before it can only take what is left
+        mempool.insert(signed_transaction(1,3,300),&state).unwrap();
+        assert_eq!(mempool.insert(signed_transaction(1,2,101),&state),
+            Err(MempoolError::Invalid(TransactionError::InsufficientBalance{balance:amount(100),cost:amount(101)})));
+        // a replacement does not pay for the transaction it replaces
+        let replaced=mempool.insert(signed_transaction_with_fee(1,1,100,1),&state).unwrap();
+        assert!(replaced.is_some());
+        mempool.insert(signed_transaction(1,2,500),&state).unwrap();
+        assert_eq!(mempool.get_size(), 3);
+    }
+
+    #[test]
+    fn escrow_releases_are_checked_on_admission() {
+        let mut state=funded_state();
+        let escrow=Escrow{sender:ico_address(1),receiver:ico_address(2),value:amount(300),hashlock:hashlock(b"secret"),timeout:5};
+        let id=H256::from([7;32]);
+        state.escrows.insert(id,escrow);
+        let claim=|sender,escrow,preimage:&[u8]| transaction_of_kind(sender,1,TransactionKind::Claim{escrow,preimage:preimage.to_vec()});
+        let mut mempool=Mempool::new();
+        let rejected=[
+            (claim(2,id,b"guess"),TransactionError::BadPreimage),
+            (claim(3,id,b"secret"),TransactionError::NotEscrowParty),
+            (claim(2,H256::default(),b"secret"),TransactionError::UnknownEscrow),
+            (transaction_of_kind(1,1,TransactionKind::Refund{escrow:id}),TransactionError::EscrowLocked{timeout:5,height:1}),
+        ];
+        for (transaction,error) in rejected.iter(){
+            assert_eq!(mempool.insert(transaction.clone(),&state), Err(MempoolError::Invalid(error.clone())));
+        }
+        assert!(mempool.is_empty());
+        mempool.insert(claim(2,id,b"secret"),&state).unwrap();
+    }
+
+    #[test]
+    fn scripts_run_on_admission() {
+        let mut state=funded_state();
+        let key=ico_key_pair(2);
+        let script=Script(vec![Op::Push(key.public_key().as_ref().to_vec()),Op::CheckSig]);
+        state.add_account(script.address(),amount(1000));
+        let raw=Transaction{sender:script.address(),..signed_transaction(1,1,10).as_account().unwrap().trans_raw.clone()};
+        let spend=|signature:Vec<u8>| SignedTransaction::scripted(raw.clone(),ScriptWitness{script:script.clone(),args:vec![signature]}).into();
+        let mut mempool=Mempool::new();
+        let forged=ico_key_pair(1).sign(&signing_message(&raw,&mempool.chain_id())).as_ref().to_vec();
+        assert_eq!(mempool.insert(spend(forged),&state), Err(MempoolError::Invalid(TransactionError::Script(ScriptError::False))));
+        assert!(mempool.is_empty());
+        let signature=key.sign(&signing_message(&raw,&mempool.chain_id())).as_ref().to_vec();
+        mempool.insert(spend(signature),&state).unwrap();
+    }
+
+    #[test]
+    fn token_rules_are_checked_on_admission() {
+        let mut state=funded_state();
+        let token=H256::from([9;32]);
+        state.tokens.insert(token,Token{issuer:ico_address(1),supply:100});
+        state.token_balances.insert((token,ico_address(1)),100);
+        let transfer=|sender,nonce,token,amount| transaction_of_kind(sender,nonce,TransactionKind::TokenTransfer{token,amount});
+        let create=transaction_of_kind(3,1,TransactionKind::TokenCreate{supply:5});
+        state.tokens.insert(create.as_account().unwrap().trans_raw.hash(),Token{issuer:ico_address(3),supply:5});
+        let mut mempool=Mempool::new();
+        let rejected=[
+            (transfer(2,1,token,1),TransactionError::InsufficientTokens{balance:0,amount:1}),
+            (transfer(1,1,H256::default(),1),TransactionError::UnknownToken),
+            (transaction_of_kind(2,1,TransactionKind::TokenMint{token,amount:1}),TransactionError::NotIssuer),
+            (create,TransactionError::TokenExists),
+        ];
+        for (transaction,error) in rejected.iter(){
+            assert_eq!(mempool.insert(transaction.clone(),&state), Err(MempoolError::Invalid(error.clone())));
+        }
+        assert!(mempool.is_empty());
+        // the sender's other transfers of the token come off its token balance first
+        mempool.insert(transfer(1,1,token,60),&state).unwrap();
+        assert_eq!(mempool.insert(transfer(1,2,token,50),&state),
+            Err(MempoolError::Invalid(TransactionError::InsufficientTokens{balance:40,amount:50})));
+        mempool.insert(transfer(1,2,token,40),&state).unwrap();
+        mempool.insert(transaction_of_kind(1,3,TransactionKind::TokenMint{token,amount:1}),&state).unwrap();
+        assert_eq!(mempool.get_size(), 3);
+    }
+
+    #[test]
+    fn connected_blocks_invalidate_pooled_releases_and_transfers() {
+        let mut blockchain=Blockchain::new();
+        let events=blockchain.subscribe();
+        let mut mempool=Mempool::new();
+        let raw=signed_transaction(1,1,0).as_account().unwrap().trans_raw.clone();
+        let lock=Transaction{receiver:ico_address(2),value:amount(300),kind:TransactionKind::Lock{hashlock:hashlock(b"secret"),timeout:2},..raw};
+        let escrow=lock.hash();
+        let lock:LedgerTransaction=SignedTransaction::from_raw(lock,&mempool.chain_id(),&ico_key_pair(1)).into();
+        let create=transaction_of_kind(3,1,TransactionKind::TokenCreate{supply:100});
+        let token=create.as_account().unwrap().trans_raw.hash();
+        let block_1=generate_block(&blockchain.tip(),vec![lock,create]);
+        blockchain.insert(&block_1);
+        mempool.handle_reorg(&events.try_recv().unwrap(),&blockchain);
+
+        let claim=transaction_of_kind(2,1,TransactionKind::Claim{escrow,preimage:b"secret".to_vec()});
+        let transfer=transaction_of_kind(3,3,TransactionKind::TokenTransfer{token,amount:60});
+        let unrelated=signed_transaction(4,1,10);
+        for transaction in [&claim,&transfer,&unrelated]{
+            mempool.insert(transaction.clone(),blockchain.tip_state()).unwrap();
+        }
+        // block 2 times the escrow out for block 3 and spends the tokens the transfer needs
+        let spend=transaction_of_kind(3,2,TransactionKind::TokenTransfer{token,amount:50});
+        let block_2=generate_block(&block_1.hash(),vec![spend]);
+        blockchain.insert(&block_2);
+        mempool.handle_reorg(&events.try_recv().unwrap(),&blockchain);
+        assert!(!mempool.contains_hash(&claim.hash()));
+        assert!(!mempool.contains_hash(&transfer.hash()));
+        assert_eq!(mempool.get_size(), 1);
+        assert!(mempool.contains_hash(&unrelated.hash()));
+    }
+
+    #[test]
+    fn only_the_owner_can_send() {
+        let state=funded_state();
+        let mut mempool=Mempool::new();
+        let raw=signed_transaction(1,1,10).as_account().unwrap().trans_raw.clone();
+        let stolen=SignedTransaction::from_raw(raw.clone(),&mempool.chain_id(),&key_pair::random());
+        assert_eq!(mempool.insert(stolen.into(),&state), Err(MempoolError::Invalid(TransactionError::NotOwner)));
+        let other_chain=SignedTransaction::from_raw(raw,&H256::default(),&ico_key_pair(1));
+        assert_eq!(mempool.insert(other_chain.into(),&state), Err(MempoolError::Invalid(TransactionError::BadSignature)));
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn replacement_needs_a_fee_bump() {
+        let state=funded_state();
+        let mut mempool=Mempool::new();
+        let first=signed_transaction_with_fee(1,1,10,20);
+        let second=signed_transaction_with_fee(1,2,10,20);
+        mempool.insert(first.clone(),&state).unwrap();
+        mempool.insert(second.clone(),&state).unwrap();
+        assert_eq!(mempool.insert(signed_transaction_with_fee(1,1,50,21),&state),
+            Err(MempoolError::ReplacementUnderpriced{fee:amount(21),required:amount(22)}));
+        let replacement=signed_transaction_with_fee(1,1,50,22);
+        let replaced=mempool.insert(replacement.clone(),&state).unwrap();
+        assert_eq!(replaced.map(|transaction| transaction.hash()), Some(first.hash()));
+        assert!(!mempool.contains_hash(&first.hash()));
+        assert_eq!(mempool.get_size(), 2);
+        // the replacement takes the old one's place in front of the second
+        let pending:Vec<H256>=mempool.pending()[0].iter().map(|transaction| transaction.hash()).collect();
+        assert_eq!(pending, vec![replacement.hash(),second.hash()]);
+
+        let mut mempool=Mempool::new().with_replacement_bump(50);
+        mempool.insert(first,&state).unwrap();
+        assert_eq!(mempool.insert(signed_transaction_with_fee(1,1,50,29),&state),
+            Err(MempoolError::ReplacementUnderpriced{fee:amount(29),required:amount(30)}));
+        assert!(mempool.insert(signed_transaction_with_fee(1,1,50,30),&state).unwrap().is_some());
+        let second=signed_transaction(1,2,500);
+        mempool.insert(second.clone(),&state).unwrap();
+        // once a block takes some of the balance, the transactions it no longer covers go
+        let mut next_state=state.clone();
+        next_state.add_an_account(ico_address(1),0,amount(550));
+        mempool.check_valid(&next_state);
+        assert!(!mempool.contains_hash(&second.hash()));
+        assert_eq!(mempool.get_size(), 1);
+        assert_eq!(mempool.pending()[0][0].hash(), signed_transaction_with_fee(1,1,50,30).hash());
+    }
+
+    #[test]
+    fn transactions_expire() {
+        let state=funded_state();
+        let mut mempool=Mempool::new().with_lifetime(1000);
+        let old=signed_transaction_with_fee(1,1,10,2);
+        let new=signed_transaction_with_fee(2,1,10,2);
+        mempool.insert_at(old.clone(),&state,0).unwrap();
+        mempool.insert_at(new.clone(),&state,500).unwrap();
+        mempool.expire(999);
+        assert_eq!(mempool.get_size(), 2);
+        mempool.expire(1000);
+        assert!(!mempool.contains_hash(&old.hash()));
+        assert!(mempool.contains_hash(&new.hash()));
+
+        // a transaction valid until height 2 may go into block 2 but not block 3
+        let mut raw=signed_transaction(3,1,10).as_account().unwrap().trans_raw.clone();
+        raw.valid_until_height=Some(2);
+        let bounded:LedgerTransaction=SignedTransaction::from_raw(raw,&Block::genesis().hash(),&ico_key_pair(3)).into();
+        let mut next_state=state.clone();
+        next_state.height=1;
+        mempool.insert(bounded.clone(),&next_state).unwrap();
+        next_state.height=2;
+        mempool.check_valid(&next_state);
+        assert!(!mempool.contains_hash(&bounded.hash()));
+        assert!(mempool.contains_hash(&new.hash()));
+        assert_eq!(mempool.insert(bounded,&next_state),
+            Err(MempoolError::Invalid(TransactionError::Expired{valid_until:2,height:3})));
+    }
+
+    #[test]
+    fn saved_mempool_is_revalidated_on_load() {
+        let dir=std::env::temp_dir().join(format!("bitcoin-mempool-{}",rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path=dir.join("mempool.dat");
+        let state=funded_state();
+        let mut mempool=Mempool::new();
+        let first=signed_transaction_with_fee(1,1,10,2);
+        let second=signed_transaction_with_fee(1,2,10,2);
+        let other=signed_transaction_with_fee(2,1,10,2);
+        for transaction in [&second,&first,&other].iter(){
+            mempool.insert((*transaction).clone(),&state).unwrap();
+        }
+        // one that entered long ago has waited for the lifetime while the node was down
+        let stale=signed_transaction_with_fee(3,1,10,2);
+        mempool.insert_at(stale.clone(),&state,0).unwrap();
+        mempool.save(&path).unwrap();
+
+        let mut reloaded=Mempool::new();
+        assert_eq!(reloaded.load(&path,&state).unwrap(), 3);
+        assert!(!reloaded.contains_hash(&stale.hash()));
+        assert_eq!(reloaded.queued_size(), 0);
+        // in the meantime the tip took sender 1's first nonce
+        let mut next_state=state.clone();
+        next_state.add_an_account(ico_address(1),1,amount(990));
+        let mut reloaded=Mempool::new();
+        assert_eq!(reloaded.load(&path,&next_state).unwrap(), 2);
+        assert!(!reloaded.contains_hash(&first.hash()));
+        assert!(reloaded.contains_hash(&second.hash()));
+
+        // a file of another version is refused
+        let mut bytes=std::fs::read(&path).unwrap();
+        bytes[4]+=1;
+        std::fs::write(&path,bytes).unwrap();
+        assert_eq!(Mempool::new().load(&path,&state).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(Mempool::new().load(&dir.join("missing.dat"),&state).unwrap(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn utxo_inputs_conflict() {
+        let ico=ico_transaction();
+        let state=UtxoSet::from_genesis(&ico);
+        let mut mempool=Mempool::new();
+        let spend=|receiver:u8,fee:u64|->LedgerTransaction{
+            let outputs=vec![TxOutput{value:amount(1000-fee),recipient:ico_address(receiver)}];
+            let mut transaction=UtxoTransaction::new(vec![ico.outpoint(0)],outputs,amount(fee));
+            transaction.sign_input(0,&Block::genesis().hash(),&ico_key_pair(1));
+            transaction.into()
+        };
+        let first=spend(2,5);
+        mempool.insert(first.clone(),&state).unwrap();
+        assert!(mempool.is_spent(&ico.outpoint(0)));
+        assert_eq!(mempool.pending(), vec![vec![&first]]);
+        // the first one to spend an output keeps it, whatever the fee
+        assert_eq!(mempool.insert(spend(3,50),&state), Err(MempoolError::Conflict{hash:first.hash()}));
+        assert_eq!(mempool.insert(signed_transaction(1,1,10),&state), Err(MempoolError::Invalid(TransactionError::WrongLedger)));
+
+        mempool.remove_transaction(vec![first.clone()]);
+        assert!(!mempool.is_spent(&ico.outpoint(0)));
+        mempool.insert(spend(3,50),&state).unwrap();
+        // once the tip spends the output, the transaction is dropped
+        let mut next_state=state.clone();
+        next_state.apply_transaction(&first,&BlockContext::default()).unwrap();
+        mempool.check_valid(&next_state);
+        assert!(mempool.is_empty());
+        assert!(!mempool.is_spent(&ico.outpoint(0)));
+    }
+}
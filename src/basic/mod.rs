@@ -1,4 +1,7 @@
+pub mod amount;
 pub mod block;
+pub mod ledger;
 pub mod mempool;
 pub mod state;
+pub mod utxo_set;
 pub mod key_pair;
\ No newline at end of file
@@ -1,34 +1,324 @@
-use std::{collections::HashMap, fmt::{Display, self, write}};
+use std::{collections::{BTreeMap, HashMap}, fmt};
 use crate::api::address::H160;
-#[derive(Clone,Debug)]
+use crate::basic::amount::Amount;
+use crate::basic::ledger::{check_expiry, BlockContext, Ledger, LedgerModel};
+use crate::crypto::hash::{H256, Hashable};
+use crate::transaction::htlc::Escrow;
+use crate::transaction::script::ScriptContext;
+use crate::transaction::token::Token;
+use crate::transaction::transaction::{signing_message, LedgerTransaction, SignedTransaction, TransactionError, TransactionKind};
+use crate::transaction::utxo::{OutPoint, TxOutput};
+/// The ledger of the account model
+#[derive(Clone,Debug,Default)]
 pub struct State{
-    pub accounts:HashMap<H160,(usize,usize)>//HashMap<account address, (account nonce, balance)>
+    pub accounts:HashMap<H160,(usize,Amount)>,//HashMap<account address, (account nonce, balance)>
+    pub immature:BTreeMap<u64,(H160,Amount)>,//block height -> (miner, reward) not credited yet
+    pub escrows:HashMap<H256,Escrow>,//locked value not claimed or refunded yet
+    pub tokens:HashMap<H256,Token>,//token id -> issuer and supply
+    pub token_balances:HashMap<(H256,H160),u64>,//(token id, holder) -> units held, never 0
+    pub height:u64,//the height of the block this is the state after
+}
+/// What some changes did to a ledger, so they can be reverted.
+/// Every entry is an account, output, escrow, token, token balance or reward as it was before it was first touched.
+#[derive(Clone,Debug,Default)]
+pub struct StateUndo{
+    pub(crate) accounts:Vec<(H160,Option<(usize,Amount)>)>,
+    pub(crate) utxos:Vec<(OutPoint,Option<TxOutput>)>,
+    pub(crate) escrows:Vec<(H256,Option<Escrow>)>,
+    pub(crate) tokens:Vec<(H256,Option<Token>)>,
+    pub(crate) token_balances:Vec<((H256,H160),Option<u64>)>,
+    pub(crate) immature:Vec<(u64,Option<(H160,Amount)>)>,
+    pub(crate) height:Option<u64>,
+}
+impl StateUndo{
+    /// Add the changes made after the ones already recorded
+    pub fn append(&mut self,mut later:StateUndo){
+        self.accounts.append(&mut later.accounts);
+        self.utxos.append(&mut later.utxos);
+        self.escrows.append(&mut later.escrows);
+        self.tokens.append(&mut later.tokens);
+        self.token_balances.append(&mut later.token_balances);
+        self.immature.append(&mut later.immature);
+        self.height=self.height.or(later.height);
+    }
 }
 impl State{
     pub fn new()->Self{
-        let accounts:HashMap<H160,(usize,usize)>=HashMap::new();
-        Self { accounts}
+        let accounts:HashMap<H160,(usize,Amount)>=HashMap::new();
+        Self { accounts,immature:BTreeMap::new(),escrows:HashMap::new(),tokens:HashMap::new(),token_balances:HashMap::new(),height:0}
     }
-    pub fn add_account(&mut self,addr:H160,balance:usize){
-        self.accounts.insert(addr.clone(), (0,balance));
+    pub fn add_account(&mut self,addr:H160,balance:Amount){
+        self.accounts.insert(addr, (0,balance));
     }
-    pub fn add_an_account(&mut self,addr:H160,nonce:usize,balance:usize){
-        self.accounts.insert(addr.clone(), (nonce,balance));
+    pub fn add_an_account(&mut self,addr:H160,nonce:usize,balance:Amount){
+        self.accounts.insert(addr, (nonce,balance));
     }
-    pub fn get_accounts(&self)->HashMap<H160,(usize,usize)>{
+    pub fn get_accounts(&self)->HashMap<H160,(usize,Amount)>{
         self.accounts.clone()
     }
     pub fn contains_address(&self,addr:&H160)->bool{
         self.accounts.contains_key(addr)
     }
+    /// Set an account and remember what it was before
+    fn set_account(&mut self,addr:H160,account:(usize,Amount),undo:&mut StateUndo){
+        let prev=self.accounts.insert(addr,account);
+        undo.accounts.push((addr,prev));
+    }
+    /// Set or remove an escrow and remember what it was before
+    fn set_escrow(&mut self,id:H256,escrow:Option<Escrow>,undo:&mut StateUndo){
+        let prev=match escrow{
+            Some(escrow)=>self.escrows.insert(id,escrow),
+            None=>self.escrows.remove(&id),
+        };
+        undo.escrows.push((id,prev));
+    }
+    /// Set a token and remember what it was before
+    fn set_token(&mut self,id:H256,token:Token,undo:&mut StateUndo){
+        let prev=self.tokens.insert(id,token);
+        undo.tokens.push((id,prev));
+    }
+    /// Set the units of a token an address holds, dropping the entry at 0, and remember what it was before
+    fn set_token_balance(&mut self,key:(H256,H160),balance:u64,undo:&mut StateUndo){
+        let prev=if balance==0{
+            self.token_balances.remove(&key)
+        }else{
+            self.token_balances.insert(key,balance)
+        };
+        undo.token_balances.push((key,prev));
+    }
+    /// Move `amount` units of a token from `sender` to `receiver`, which `check_rules` allowed
+    fn transfer_tokens(&mut self,token:H256,sender:H160,receiver:H160,amount:u64,undo:&mut StateUndo){
+        let left=self.token_balance(&token,&sender)-amount;
+        self.set_token_balance((token,sender),left,undo);
+        // the supply caps every balance, so this cannot overflow
+        let received=self.token_balance(&token,&receiver)+amount;
+        self.set_token_balance((token,receiver),received,undo);
+    }
+    /// Issue `amount` more units of a token to `receiver`, which `check_rules` allowed
+    fn mint_tokens(&mut self,token:H256,receiver:H160,amount:u64,undo:&mut StateUndo){
+        let mut minted=self.tokens[&token].clone();
+        minted.supply+=amount;
+        self.set_token(token,minted,undo);
+        let received=self.token_balance(&token,&receiver)+amount;
+        self.set_token_balance((token,receiver),received,undo);
+    }
+    /// Add `value` to an account, creating it if needed
+    fn credit(&mut self,addr:H160,value:Amount,undo:&mut StateUndo)->Result<(),TransactionError>{
+        let (nonce,balance)=self.accounts.get(&addr).copied().unwrap_or((0,Amount::ZERO));
+        let balance=balance.checked_add(value).ok_or(TransactionError::Overflow)?;
+        self.set_account(addr,(nonce,balance),undo);
+        Ok(())
+    }
+    /// Remove an escrow that `check_rules` let a claim or refund release, and return it
+    fn release_escrow(&mut self,id:&H256,undo:&mut StateUndo)->Result<Escrow,TransactionError>{
+        let escrow=self.escrows.get(id).cloned().ok_or(TransactionError::UnknownEscrow)?;
+        self.set_escrow(*id,None,undo);
+        Ok(escrow)
+    }
+    /// Apply an account transaction.
+    /// It must be signed with the sender's key, or unlock the sender's script in the block described by `context`.
+    /// The sender's nonce must be the next one and its balance must cover the value and the fee.
+    /// The fee leaves the ledger here; it comes back through the block's coinbase.
+    /// A lock moves the value into an escrow named by the raw transaction's hash, and a claim or a refund moves it out to the sender.
+    /// A batch pays all of its outputs or, if one of them fails, none.
+    /// A token create issues a token named by the raw transaction's hash, and token transfers and mints move or issue its units.
+    /// A cost or a receiver's balance above the total supply is rejected.
+    fn apply_signed(&mut self,transaction:&SignedTransaction,context:&BlockContext)->Result<StateUndo,TransactionError>{
+        let raw=&transaction.trans_raw;
+        if !transaction.signed_by_sender(){
+            return Err(TransactionError::NotOwner);
+        }
+        check_rules(self,transaction,self.height+1,context,0)?;
+        let (sender_nonce,sender_balance)=*self.accounts.get(&raw.sender).ok_or(TransactionError::UnknownSender)?;
+        if raw.nonce!=sender_nonce+1{
+            return Err(TransactionError::BadNonce{expected:sender_nonce+1,got:raw.nonce});
+        }
+        let cost=raw.cost().ok_or(TransactionError::Overflow)?;
+        let sender_balance=sender_balance.checked_sub(cost)
+            .ok_or(TransactionError::InsufficientBalance{balance:sender_balance,cost})?;
+        let mut undo=StateUndo::default();
+        self.set_account(raw.sender,(raw.nonce,sender_balance),&mut undo);
+        let result=match &raw.kind{
+            TransactionKind::Transfer=>self.credit(raw.receiver,raw.value,&mut undo),
+            TransactionKind::Lock{hashlock,timeout}=>{
+                let escrow=Escrow{sender:raw.sender,receiver:raw.receiver,value:raw.value,hashlock:*hashlock,timeout:*timeout};
+                self.set_escrow(raw.hash(),Some(escrow),&mut undo);
+                Ok(())
+            }
+            TransactionKind::Claim{escrow,..}|TransactionKind::Refund{escrow}=>self.release_escrow(escrow,&mut undo)
+                .and_then(|escrow| self.credit(raw.sender,escrow.value,&mut undo)),
+            TransactionKind::Batch{outputs}=>outputs.iter().try_for_each(|(receiver,value)| self.credit(*receiver,*value,&mut undo)),
+            TransactionKind::TokenCreate{supply}=>{
+                let id=raw.hash();
+                self.set_token(id,Token{issuer:raw.sender,supply:*supply},&mut undo);
+                self.set_token_balance((id,raw.sender),*supply,&mut undo);
+                Ok(())
+            }
+            TransactionKind::TokenTransfer{token,amount}=>{
+                self.transfer_tokens(*token,raw.sender,raw.receiver,*amount,&mut undo);
+                Ok(())
+            }
+            TransactionKind::TokenMint{token,amount}=>{
+                self.mint_tokens(*token,raw.receiver,*amount,&mut undo);
+                Ok(())
+            }
+        };
+        if let Err(error)=result{
+            self.revert(undo);
+            return Err(error);
+        }
+        Ok(undo)
+    }
+}
+/// Check what an account transaction needs from `ledger` in a block at `height` described by
+/// `context`, without changing anything: the sender's script, if it has one, has to pass, a
+/// claim or refund has to be able to release its escrow, and a token transaction has to be able
+/// to create, send or mint its units. `spent_tokens` units of the token a transfer sends are
+/// taken off the sender's token balance first, for its other transactions.
+/// The mempool checks transactions with this before letting them in.
+pub fn check_rules(ledger:&dyn Ledger,transaction:&SignedTransaction,height:u64,context:&BlockContext,spent_tokens:u64)->Result<(),TransactionError>{
+    let raw=&transaction.trans_raw;
+    if let Some(witness)=&transaction.script{
+        let message=signing_message(raw,&context.chain_id);
+        let script_context=ScriptContext{message:&message,height,time:context.time};
+        witness.script.run(&witness.args,&script_context).map_err(TransactionError::Script)?;
+    }
+    match &raw.kind{
+        TransactionKind::Claim{escrow,..}|TransactionKind::Refund{escrow}=>{
+            ledger.escrow(escrow).ok_or(TransactionError::UnknownEscrow)?.check_release(raw.sender,&raw.kind,height)
+        }
+        TransactionKind::TokenCreate{..}=>match ledger.token(&raw.hash()){
+            Some(_)=>Err(TransactionError::TokenExists),
+            None=>Ok(()),
+        },
+        TransactionKind::TokenTransfer{token,amount}=>{
+            ledger.token(token).ok_or(TransactionError::UnknownToken)?;
+            let balance=ledger.token_balance(token,&raw.sender).saturating_sub(spent_tokens);
+            if balance<*amount{
+                return Err(TransactionError::InsufficientTokens{balance,amount:*amount});
+            }
+            Ok(())
+        }
+        TransactionKind::TokenMint{token,amount}=>{
+            let current=ledger.token(token).ok_or(TransactionError::UnknownToken)?;
+            if current.issuer!=raw.sender{
+                return Err(TransactionError::NotIssuer);
+            }
+            current.minted(*amount).map(|_| ()).ok_or(TransactionError::Overflow)
+        }
+        _=>Ok(()),
+    }
+}
+impl Ledger for State{
+    fn model(&self)->LedgerModel{
+        LedgerModel::Account
+    }
+    fn height(&self)->u64{
+        self.height
+    }
+    fn apply_transaction(&mut self,transaction:&LedgerTransaction,context:&BlockContext)->Result<StateUndo,TransactionError>{
+        check_expiry(transaction,self.height+1)?;
+        match transaction{
+            LedgerTransaction::Account(transaction)=>self.apply_signed(transaction,context),
+            LedgerTransaction::Utxo(_)=>Err(TransactionError::WrongLedger),
+        }
+    }
+    fn add_reward(&mut self,height:u64,miner:H160,value:Amount)->StateUndo{
+        let mut undo=StateUndo::default();
+        let prev=self.immature.insert(height,(miner,value));
+        undo.immature.push((height,prev));
+        undo
+    }
+    fn mature_rewards(&mut self,height:u64)->Option<StateUndo>{
+        let mut undo=StateUndo::default();
+        let matured:Vec<(u64,(H160,Amount))>=self.immature.range(..=height).map(|(h,reward)| (*h,*reward)).collect();
+        for (reward_height,(miner,value)) in matured{
+            self.immature.remove(&reward_height);
+            undo.immature.push((reward_height,Some((miner,value))));
+            let (nonce,balance)=self.accounts.get(&miner).copied().unwrap_or((0,Amount::ZERO));
+            match balance.checked_add(value){
+                Some(balance)=>self.set_account(miner,(nonce,balance),&mut undo),
+                None=>{
+                    self.revert(undo);
+                    return None;
+                }
+            }
+        }
+        Some(undo)
+    }
+    fn set_height(&mut self,height:u64)->StateUndo{
+        let undo=StateUndo{height:Some(self.height),..StateUndo::default()};
+        self.height=height;
+        undo
+    }
+    fn revert(&mut self,undo:StateUndo){
+        for (addr,prev) in undo.accounts.into_iter().rev(){
+            match prev{
+                Some(account)=>self.accounts.insert(addr,account),
+                None=>self.accounts.remove(&addr),
+            };
+        }
+        for (id,prev) in undo.escrows.into_iter().rev(){
+            match prev{
+                Some(escrow)=>self.escrows.insert(id,escrow),
+                None=>self.escrows.remove(&id),
+            };
+        }
+        for (key,prev) in undo.token_balances.into_iter().rev(){
+            match prev{
+                Some(balance)=>self.token_balances.insert(key,balance),
+                None=>self.token_balances.remove(&key),
+            };
+        }
+        for (id,prev) in undo.tokens.into_iter().rev(){
+            match prev{
+                Some(token)=>self.tokens.insert(id,token),
+                None=>self.tokens.remove(&id),
+            };
+        }
+        for (height,prev) in undo.immature.into_iter().rev(){
+            match prev{
+                Some(reward)=>self.immature.insert(height,reward),
+                None=>self.immature.remove(&height),
+            };
+        }
+        if let Some(height)=undo.height{
+            self.height=height;
+        }
+    }
+    fn immature_rewards(&self)->&BTreeMap<u64,(H160,Amount)>{
+        &self.immature
+    }
+    fn account(&self,address:&H160)->Option<(usize,Amount)>{
+        self.accounts.get(address).copied()
+    }
+    fn escrow(&self,id:&H256)->Option<&Escrow>{
+        self.escrows.get(id)
+    }
+    fn token(&self,id:&H256)->Option<&Token>{
+        self.tokens.get(id)
+    }
+    fn token_balance(&self,token:&H256,address:&H160)->u64{
+        self.token_balances.get(&(*token,*address)).copied().unwrap_or(0)
+    }
+    fn unspent_output(&self,_outpoint:&OutPoint)->Option<&TxOutput>{
+        None
+    }
+    fn outputs_of(&self,_owner:&H160)->Vec<(OutPoint,TxOutput)>{
+        Vec::new()
+    }
+    fn box_clone(&self)->Box<dyn Ledger>{
+        Box::new(self.clone())
+    }
 }
 impl fmt::Display for State{
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f,"total size of accounts {}\n",self.accounts.len());
-        for (addr,(nonce,balance)) in self.accounts.iter(){
-            write!(f,"addr is {}\n balance is {}\n",addr,balance);
+        writeln!(f,"total size of accounts {}",self.accounts.len())?;
+        for (addr,(_nonce,balance)) in self.accounts.iter(){
+            writeln!(f,"addr is {}\n balance is {}",addr,balance)?;
         }
+        writeln!(f,"total locked escrows {}",self.escrows.len())?;
+        writeln!(f,"total tokens {}",self.tokens.len())?;
         Ok(())
     }
 }
-
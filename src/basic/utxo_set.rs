@@ -0,0 +1,268 @@
+//! The ledger of the UTXO model.
+
+use crate::api::address::H160 as Address;
+use crate::basic::amount::Amount;
+use crate::basic::block::Coinbase;
+use crate::basic::ledger::{check_expiry, BlockContext, Ledger, LedgerModel};
+use crate::basic::state::StateUndo;
+use crate::crypto::hash::{H256, Hashable};
+use crate::transaction::htlc::Escrow;
+use crate::transaction::token::Token;
+use crate::transaction::transaction::{LedgerTransaction, TransactionError};
+use crate::transaction::utxo::{OutPoint, TxOutput, UtxoTransaction};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+/// The outputs that can be spent.
+///
+/// A matured block reward becomes an output of its own, see `reward_outpoint`.
+#[derive(Clone, Debug, Default)]
+pub struct UtxoSet {
+    utxos: HashMap<OutPoint, TxOutput>,
+    pub immature: BTreeMap<u64, (Address, Amount)>,
+    pub height: u64,
+}
+
+/// Where the reward of the block at `height` can be spent once it matures
+pub fn reward_outpoint(height: u64, miner: Address, value: Amount) -> OutPoint {
+    OutPoint { txid: Coinbase { height, miner, value }.hash(), index: 0 }
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The ledger after the genesis transaction, which spends nothing and only creates outputs
+    pub fn from_genesis(transaction: &UtxoTransaction) -> Self {
+        let mut set = Self::new();
+        for (index, output) in transaction.outputs.iter().enumerate() {
+            set.utxos.insert(transaction.outpoint(index), output.clone());
+        }
+        set
+    }
+
+    pub fn len(&self) -> usize {
+        self.utxos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.utxos.is_empty()
+    }
+
+    /// Set or remove an output and remember what it was before
+    fn set_output(&mut self, outpoint: OutPoint, output: Option<TxOutput>, undo: &mut StateUndo) {
+        let prev = match output {
+            Some(output) => self.utxos.insert(outpoint, output),
+            None => self.utxos.remove(&outpoint),
+        };
+        undo.utxos.push((outpoint, prev));
+    }
+
+    /// Apply a UTXO transaction, see `check_spends`
+    fn apply_utxo(&mut self, transaction: &UtxoTransaction) -> Result<StateUndo, TransactionError> {
+        check_spends(self, transaction)?;
+        let mut undo = StateUndo::default();
+        for input in transaction.inputs.iter() {
+            self.set_output(input.previous, None, &mut undo);
+        }
+        for (index, output) in transaction.outputs.iter().enumerate() {
+            self.set_output(transaction.outpoint(index), Some(output.clone()), &mut undo);
+        }
+        Ok(undo)
+    }
+}
+
+/// Check a UTXO transaction against `ledger` without applying it. Every input must spend a
+/// different unspent output and be signed by the output's owner, and the inputs must add up to
+/// the outputs plus the fee.
+pub fn check_spends(ledger: &dyn Ledger, transaction: &UtxoTransaction) -> Result<(), TransactionError> {
+    let mut seen = HashSet::new();
+    let mut inputs = Amount::ZERO;
+    for (index, input) in transaction.inputs.iter().enumerate() {
+        let output = match ledger.unspent_output(&input.previous) {
+            Some(output) if seen.insert(input.previous) => output,
+            _ => return Err(TransactionError::MissingInput { index }),
+        };
+        if Address::from_pubkey(&input.pub_key) != output.recipient {
+            return Err(TransactionError::NotOwner);
+        }
+        inputs = inputs.checked_add(output.value).ok_or(TransactionError::Overflow)?;
+    }
+    let spent = transaction.spent().ok_or(TransactionError::Overflow)?;
+    if transaction.inputs.is_empty() || inputs != spent {
+        return Err(TransactionError::Unbalanced { inputs, spent });
+    }
+    Ok(())
+}
+
+impl Ledger for UtxoSet {
+    fn model(&self) -> LedgerModel {
+        LedgerModel::Utxo
+    }
+
+    fn height(&self) -> u64 {
+        self.height
+    }
+
+    fn apply_transaction(
+        &mut self,
+        transaction: &LedgerTransaction,
+        _context: &BlockContext,
+    ) -> Result<StateUndo, TransactionError> {
+        check_expiry(transaction, self.height + 1)?;
+        match transaction {
+            LedgerTransaction::Utxo(transaction) => self.apply_utxo(transaction),
+            LedgerTransaction::Account(_) => Err(TransactionError::WrongLedger),
+        }
+    }
+
+    fn add_reward(&mut self, height: u64, miner: Address, value: Amount) -> StateUndo {
+        let mut undo = StateUndo::default();
+        let prev = self.immature.insert(height, (miner, value));
+        undo.immature.push((height, prev));
+        undo
+    }
+
+    fn mature_rewards(&mut self, height: u64) -> Option<StateUndo> {
+        let mut undo = StateUndo::default();
+        let matured: Vec<(u64, (Address, Amount))> =
+            self.immature.range(..=height).map(|(h, reward)| (*h, *reward)).collect();
+        for (reward_height, (miner, value)) in matured {
+            self.immature.remove(&reward_height);
+            undo.immature.push((reward_height, Some((miner, value))));
+            let output = TxOutput { value, recipient: miner };
+            self.set_output(reward_outpoint(reward_height, miner, value), Some(output), &mut undo);
+        }
+        Some(undo)
+    }
+
+    fn set_height(&mut self, height: u64) -> StateUndo {
+        let undo = StateUndo { height: Some(self.height), ..StateUndo::default() };
+        self.height = height;
+        undo
+    }
+
+    fn revert(&mut self, undo: StateUndo) {
+        for (outpoint, prev) in undo.utxos.into_iter().rev() {
+            match prev {
+                Some(output) => self.utxos.insert(outpoint, output),
+                None => self.utxos.remove(&outpoint),
+            };
+        }
+        for (height, prev) in undo.immature.into_iter().rev() {
+            match prev {
+                Some(reward) => self.immature.insert(height, reward),
+                None => self.immature.remove(&height),
+            };
+        }
+        if let Some(height) = undo.height {
+            self.height = height;
+        }
+    }
+
+    fn immature_rewards(&self) -> &BTreeMap<u64, (Address, Amount)> {
+        &self.immature
+    }
+
+    fn account(&self, _address: &Address) -> Option<(usize, Amount)> {
+        None
+    }
+
+    fn escrow(&self, _id: &H256) -> Option<&Escrow> {
+        None
+    }
+
+    fn token(&self, _id: &H256) -> Option<&Token> {
+        None
+    }
+
+    fn token_balance(&self, _token: &H256, _address: &Address) -> u64 {
+        0
+    }
+
+    fn unspent_output(&self, outpoint: &OutPoint) -> Option<&TxOutput> {
+        self.utxos.get(outpoint)
+    }
+
+    fn outputs_of(&self, owner: &Address) -> Vec<(OutPoint, TxOutput)> {
+        let mut outputs: Vec<(OutPoint, TxOutput)> = self
+            .utxos
+            .iter()
+            .filter(|(_, output)| output.recipient == *owner)
+            .map(|(outpoint, output)| (*outpoint, output.clone()))
+            .collect();
+        outputs.sort_by_key(|(outpoint, _)| *outpoint);
+        outputs
+    }
+
+    fn box_clone(&self) -> Box<dyn Ledger> {
+        Box::new(self.clone())
+    }
+}
+
+impl fmt::Display for UtxoSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "total unspent outputs {}", self.utxos.len())?;
+        for output in self.utxos.values() {
+            writeln!(f, "addr is {}\n value is {}", output.recipient, output.value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::blockchain::blockchain::{ico_address, ico_key_pair, ico_transaction};
+
+    fn amount(units: u64) -> Amount {
+        Amount::from_base_units(units)
+    }
+
+    /// Account 1 sends 500 of its 1000 to account 2 and the rest less the fee back to itself
+    fn spend(key: u8, fee: u64) -> LedgerTransaction {
+        let outputs = vec![
+            TxOutput { value: amount(500), recipient: ico_address(2) },
+            TxOutput { value: amount(490), recipient: ico_address(1) },
+        ];
+        let mut transaction = UtxoTransaction::new(vec![ico_transaction().outpoint(0)], outputs, amount(fee));
+        transaction.sign_input(0, &H256::default(), &ico_key_pair(key));
+        transaction.into()
+    }
+
+    #[test]
+    fn spends_and_reverts() {
+        let mut set = UtxoSet::from_genesis(&ico_transaction());
+        let before = set.outputs_of(&ico_address(1));
+        assert_eq!(before.len(), 1);
+        assert_eq!(set.apply_transaction(&spend(2, 10), &BlockContext::default()).unwrap_err(), TransactionError::NotOwner);
+        assert_eq!(
+            set.apply_transaction(&spend(1, 9), &BlockContext::default()).unwrap_err(),
+            TransactionError::Unbalanced { inputs: amount(1000), spent: amount(999) }
+        );
+
+        let undo = set.apply_transaction(&spend(1, 10), &BlockContext::default()).unwrap();
+        assert_eq!(set.outputs_of(&ico_address(1))[0].1.value, amount(490));
+        assert_eq!(set.outputs_of(&ico_address(2)).len(), 2);
+        assert_eq!(set.apply_transaction(&spend(1, 10), &BlockContext::default()).unwrap_err(), TransactionError::MissingInput { index: 0 });
+        set.revert(undo);
+        assert_eq!(set.outputs_of(&ico_address(1)), before);
+        assert_eq!(set.outputs_of(&ico_address(2)).len(), 1);
+    }
+
+    #[test]
+    fn matured_reward_is_an_output() {
+        let mut set = UtxoSet::new();
+        let miner = ico_address(7);
+        set.add_reward(1, miner, amount(50));
+        assert!(set.is_empty());
+        let undo = set.mature_rewards(1).unwrap();
+        let outpoint = reward_outpoint(1, miner, amount(50));
+        assert_eq!(set.unspent_output(&outpoint), Some(&TxOutput { value: amount(50), recipient: miner }));
+        assert!(set.immature.is_empty());
+        set.revert(undo);
+        assert!(set.is_empty());
+        assert_eq!(set.immature[&1], (miner, amount(50)));
+    }
+}
@@ -2,103 +2,391 @@
 //! 
 //! You need to implement the `Blockchain` struct and its methods.
 
-use log::info;
-use ring::signature::KeyPair;
+use log::{info, error};
+use crossbeam::channel::{unbounded, Receiver, Sender};
 
-use crate::basic::block::{Block, self};
+use crate::basic::block::Block;
 use crate::crypto::hash::{H256, Hashable};
-use std::collections::{HashMap, VecDeque};
-use std::ops::Add;
-use std::time::{SystemTime};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::basic::state::{State, StateUndo};
+use crate::basic::ledger::{BlockContext, Ledger, LedgerModel};
+use crate::basic::utxo_set::UtxoSet;
+use crate::basic::amount::Amount;
+use crate::transaction::transaction::LedgerTransaction;
+use crate::transaction::utxo::{TxOutput, UtxoTransaction};
 use crate::basic::key_pair;
-use crate::basic::state::State;
 use crate::api::address::H160 as Address;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use super::config::ChainConfig;
+use super::orphan::OrphanPool;
+use super::store::{BlockStore, RecordKind};
+use super::tx_index::TxIndex;
+use super::validation::{check_block, check_coinbase, BlockError};
+
+/// How many recent blocks the median time past is taken over
+const MEDIAN_TIME_SPAN:usize=11;
+/// How many accounts the genesis state funds
+pub const ICO_ACCOUNTS:u8=5;
+/// The key of genesis account `i`, from 1 to `ICO_ACCOUNTS`. The seed is public, so anyone can
+/// spend these accounts; they are for testing.
+pub fn ico_key_pair(i:u8)->Ed25519KeyPair{
+    key_pair::from_seed([i;32])
+}
+/// The address of genesis account `i`
+pub fn ico_address(i:u8)->Address{
+    Address::from_pubkey(ico_key_pair(i).public_key().as_ref())
+}
+/// The balance genesis account `i` starts with
+fn ico_balance(i:u8)->Amount{
+    Amount::from_base_units(1000*i as u64)
+}
+/// The transaction of a UTXO genesis block, whose output `i-1` funds genesis account `i`
+pub fn ico_transaction()->UtxoTransaction{
+    let outputs=(1..=ICO_ACCOUNTS).map(|i| TxOutput{value:ico_balance(i),recipient:ico_address(i)}).collect();
+    UtxoTransaction::new(vec![],outputs,Amount::ZERO)
+}
+/// A change of the tip. `disconnected` lists the blocks that left the main chain, tip first,
+/// and `connected` the blocks that joined it, in chain order. A plain extension of the tip has
+/// nothing disconnected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReorgEvent{
+    pub disconnected:Vec<H256>,
+    pub connected:Vec<H256>,
+}
+/// Apply the block at `height` to `state`. Rewards that mature at this height are credited
+/// first, so the block's transactions may spend them, and the block's own coinbase is held
+/// back for `coinbase_maturity` blocks. Transactions past their `valid_until_height` are rejected,
+/// and what each kind of transaction may do, such as spend tokens, is up to the ledger.
+fn update_state(state:&mut dyn Ledger,block:&Block,height:u64,config:&ChainConfig,chain_id:&H256)->Result<StateUndo,BlockError>{
+    check_coinbase(block,height,config)?;
+    let mut undo=match height.checked_sub(config.coinbase_maturity){
+        Some(matured)=>state.mature_rewards(matured).ok_or(BlockError::RewardOverflow)?,
+        None=>StateUndo::default(),
+    };
+    let context=BlockContext{chain_id:*chain_id,time:block.header.timestamp};
+    match state.apply_transactions(&block.content.transactions,&context){
+        Ok(transactions_undo)=>undo.append(transactions_undo),
+        Err((index,error))=>{
+            state.revert(undo);
+            return Err(BlockError::InvalidTransaction{index,error});
+        }
+    }
+    if let Some(coinbase)=&block.content.coinbase{
+        undo.append(state.add_reward(height,coinbase.miner,coinbase.value));
+    }
+    undo.append(state.set_height(height));
+    Ok(undo)
+}
 pub enum Blockorigin{
     Mined,
     Recieved{delay_ms:u128}
 }
 pub struct Blockchain {
     chain_map:HashMap<H256,Block>,
-    height_map:HashMap<H256,u8>,
-    orphan_buffer:HashMap<H256,Vec<Block>>,
+    height_map:HashMap<H256,u64>,
+    main_chain:Vec<H256>,//main_chain[h] is the hash of the block at height h on the longest chain
+    orphan_pool:OrphanPool,
+    work_map:HashMap<H256,H256>,//cumulative work of the chain ending at each block
     hash_tip:H256,
-    difficulty:H256,
-    block_state:HashMap<H256,State>,
+    chain_id:H256,//the genesis hash, which transaction signatures commit to
+    config:ChainConfig,
+    state:Box<dyn Ledger>,//the state after the tip
+    undo_map:HashMap<H256,StateUndo>,//how to revert each of the most recent blocks of the main chain
+    tx_index:TxIndex,//the transactions of the main chain
+    children_map:HashMap<H256,Vec<H256>>,
+    invalid_blocks:HashSet<H256>,
+    store:Option<BlockStore>,
+    subscribers:Vec<Sender<ReorgEvent>>,
     pub hash_to_origin:HashMap<H256,Blockorigin>
 }
 
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
     pub fn new() -> Self {
-        let genis=Block::genesis();
+        Self::with_config(ChainConfig::default())
+    }
+    /// Create a new blockchain with the given consensus parameters
+    pub fn with_config(config:ChainConfig) -> Self {
+        // the genesis block commits to the whole config, so each config is a chain of its own
+        let network=config.hash();
+        let (genis,state):(Block,Box<dyn Ledger>)=match config.ledger{
+            LedgerModel::Account=>{
+                let mut state=State::new();
+                for i in 1..=ICO_ACCOUNTS{
+                    state.add_account(ico_address(i), ico_balance(i));
+                }
+                (Block::genesis_with(network,vec![]),Box::new(state))
+            }
+            LedgerModel::Utxo=>{
+                let ico=ico_transaction();
+                (Block::genesis_with(network,vec![ico.clone().into()]),Box::new(UtxoSet::from_genesis(&ico)))
+            }
+        };
         let hash=genis.hash();
+        let mut tx_index=TxIndex::new();
+        tx_index.add_block(&genis);
         let mut chain_map:HashMap<H256,Block>=HashMap::new();
-        let difficulty=genis.header.difficulty;
+        let mut work_map:HashMap<H256,H256>=HashMap::new();
+        work_map.insert(hash,genis.header.work());
         chain_map.insert(hash, genis);
-        let mut height_map:HashMap<H256,u8>=HashMap::new();
+        let mut height_map:HashMap<H256,u64>=HashMap::new();
         height_map.insert(hash,0);
 
-        let mut state=State::new();
-        for i in 1..6{
-            let addr_raw:[u8;20]=[i;20];
-            let addr=Address::new(addr_raw);
-            state.add_account(addr, 1000*i as usize);   
-        }
-        let mut block_state:HashMap<H256, State>=HashMap::new();
-        block_state.insert(hash,state);
         Blockchain {
             chain_map,
             height_map,
-            difficulty: difficulty,
-            orphan_buffer:HashMap::new(),
+            main_chain:vec![hash],
+            work_map,
+            config,
+            orphan_pool:OrphanPool::default(),
             hash_tip:hash,
+            chain_id:hash,
             hash_to_origin:HashMap::new(),
-            block_state
+            state,
+            undo_map:HashMap::new(),
+            tx_index,
+            children_map:HashMap::new(),
+            invalid_blocks:HashSet::new(),
+            store:None,
+            subscribers:Vec::new(),
+        }
+    }
+    /// Open a blockchain persisted in `dir`, creating an empty one if the directory is new.
+    ///
+    /// Stored blocks are replayed in the order they were first inserted, which rebuilds the
+    /// heights, the tip and its state exactly as they were before the restart. A directory
+    /// created with another config, ledger model included, is refused.
+    pub fn open(dir:&Path,config:ChainConfig)->io::Result<Self>{
+        let (store,records)=BlockStore::open(dir,&config)?;
+        let mut blockchain=Blockchain::with_config(config);
+        let mut orphans=Vec::new();
+        for (kind,block) in records{
+            match kind{
+                RecordKind::Block=>{
+                    if !blockchain.contain_block(&block.hash()) && blockchain.parent_check(&block){
+                        blockchain.insert(&block);
+                    }
+                }
+                RecordKind::Orphan=>orphans.push(block),
+            }
+        }
+        // orphans that were connected or dropped later have their parent in the chain
+        for block in orphans{
+            if !blockchain.parent_check(&block){
+                blockchain.add_to_orphans(&block);
+            }
         }
+        info!("reopened blockchain with {} blocks, tip {}",blockchain.block_size(),blockchain.tip());
+        blockchain.store=Some(store);
+        Ok(blockchain)
     }
-    pub fn update_state(&mut self,block:&Block)->bool{
-        let mut prev_state=self.block_state.get(&block.header.parent).unwrap().clone();
-        let mut valid=true;
-        for transaction in block.get_content(){
-            let mut sender=transaction.trans_raw.sender;
-            let mut receiver=transaction.trans_raw.receiver;
-            if sender==receiver{
-                continue;
+    fn persist(&mut self,kind:RecordKind,block:&Block){
+        if let Some(store)=self.store.as_mut(){
+            if let Err(e)=store.append(kind,block){
+                error!("failed to persist block {}: {}",block.hash(),e);
             }
-            //if did not contains the sender, add the sender's infomation
-            if !prev_state.contains_address(&receiver){
-                prev_state.add_account(receiver, 0);
+        }
+    }
+    /// Apply the tip's child `hash` to the state and make it the tip
+    fn connect_block(&mut self,hash:&H256)->Result<(),BlockError>{
+        let block=&self.chain_map[hash];
+        let undo=update_state(self.state.as_mut(),block,self.height_map[hash],&self.config,&self.chain_id)?;
+        self.undo_map.insert(*hash,undo);
+        self.tx_index.add_block(block);
+        self.main_chain.push(*hash);
+        self.hash_tip= *hash;
+        Ok(())
+    }
+    /// Revert the tip from the state and make its parent the tip
+    fn disconnect_tip(&mut self)->H256{
+        let hash=self.main_chain.pop().unwrap();
+        let undo=self.undo_map.remove(&hash).expect("undo record of a block within the reorg depth");
+        self.state.revert(undo);
+        self.tx_index.remove_block(&self.chain_map[&hash]);
+        self.hash_tip= *self.main_chain.last().unwrap();
+        hash
+    }
+    /// Switch the main chain to end at `new_tip`.
+    ///
+    /// The current chain is rolled back to the fork point and the new branch is applied block
+    /// by block. If a block of the branch is invalid, it and its descendants are marked, and the
+    /// old chain is restored unless the valid part of the branch already has more work.
+    fn reorganize(&mut self,new_tip:H256){
+        let old_tip=self.hash_tip;
+        let old_height=self.tip_height();
+        let mut branch=Vec::new();
+        let mut cur=new_tip;
+        while self.main_chain.get(self.height_map[&cur] as usize)!=Some(&cur){
+            branch.push(cur);
+            cur=self.chain_map[&cur].header.parent;
+        }
+        let fork_height=self.height_map[&cur];
+        let reachable=(fork_height+1..=old_height).all(|height| self.undo_map.contains_key(&self.main_chain[height as usize]));
+        if !reachable{
+            info!("ignoring fork {} that is {} blocks deep",new_tip,old_height-fork_height);
+            return;
+        }
+        let mut disconnected=Vec::new();
+        while self.tip_height()>fork_height{
+            disconnected.push(self.disconnect_tip());
+        }
+        for hash in branch.iter().rev(){
+            if let Err(e)=self.connect_block(hash){
+                info!("block {} is invalid: {}",hash,e);
+                self.mark_invalid(hash);
+                break;
             }
-            let accounts=prev_state.get_accounts();
-            let (sender_nonce,sender_balance)=accounts.get(&sender).unwrap();
-            let (receiver_nonce,receiver_balance)=accounts.get(&receiver).unwrap();
-            let value=transaction.trans_raw.value;
-            if sender_balance<&value{
-                valid=false;
+        }
+        if self.work_map[&self.hash_tip]<=self.work_map[&old_tip]{
+            while self.tip_height()>fork_height{
+                self.disconnect_tip();
+            }
+            for hash in disconnected.iter().rev(){
+                self.connect_block(hash).expect("reconnecting a block that was valid before");
+            }
+        }
+        // undo records are only kept for the blocks a reorg may still roll back
+        let depth=self.config.max_reorg_depth;
+        for height in old_height.saturating_sub(depth)..=self.tip_height().saturating_sub(depth){
+            self.undo_map.remove(&self.main_chain[height as usize]);
+        }
+        if self.hash_tip!=old_tip{
+            let connected=self.main_chain[fork_height as usize+1..].to_vec();
+            if !disconnected.is_empty(){
+                info!("reorganized from {} to {}: {} blocks disconnected, {} connected",old_tip,self.hash_tip,disconnected.len(),connected.len());
+            }
+            self.notify(ReorgEvent{disconnected,connected});
+        }
+    }
+    /// Get told about every change of the tip
+    pub fn subscribe(&mut self)->Receiver<ReorgEvent>{
+        let (sender,receiver)=unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+    fn notify(&mut self,event:ReorgEvent){
+        // subscribers that went away are dropped
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+    /// Run every check on a block: the block itself and its header against its parent's chain.
+    /// The transactions are checked against the state too when the block extends the tip;
+    /// a block on a side branch has its transactions checked when the branch is connected.
+    pub fn validate_block(&mut self,block:&Block)->Result<(),BlockError>{
+        check_block(block,&self.config,&self.chain_id)?;
+        let parent=block.header.parent;
+        if self.invalid_blocks.contains(&parent){
+            return Err(BlockError::InvalidAncestor);
+        }
+        if !self.contain_block(&parent){
+            return Err(BlockError::UnknownParent);
+        }
+        let target=block.header.difficulty;
+        if block.hash()>target || target>self.config.pow_limit{
+            return Err(BlockError::BadProofOfWork);
+        }
+        if target!=self.expected_difficulty(&parent){
+            return Err(BlockError::BadDifficulty);
+        }
+        if block.header.timestamp<=self.median_time_past(&parent){
+            return Err(BlockError::TimestampTooEarly);
+        }
+        let now=SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        if block.header.timestamp>now+self.config.max_future_drift_ms as u128{
+            return Err(BlockError::TimestampInFuture);
+        }
+        let height=self.height_map[&parent]+1;
+        check_coinbase(block,height,&self.config)?;
+        if parent==self.hash_tip{
+            // tried on the tip state itself and taken back, which costs the block, not the state
+            let undo=update_state(self.state.as_mut(),block,height,&self.config,&self.chain_id)?;
+            self.state.revert(undo);
+        }
+        Ok(())
+    }
+    /// The median timestamp of a block and its ancestors, up to `MEDIAN_TIME_SPAN` blocks
+    fn median_time_past(&self,hash:&H256)->u128{
+        let mut timestamps=Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut cur=*hash;
+        while timestamps.len()<MEDIAN_TIME_SPAN{
+            let block=self.get_block(&cur);
+            timestamps.push(block.header.timestamp);
+            if self.height_map[&cur]==0{
                 break;
             }
-            prev_state.add_an_account(sender, *sender_nonce+1, sender_balance-value);
-            prev_state.add_an_account(receiver, *receiver_nonce, receiver_balance+value);
-            info!("{}",prev_state);
+            cur=block.header.parent;
+        }
+        timestamps.sort_unstable();
+        timestamps[timestamps.len()/2]
+    }
+    /// Mark a block invalid, together with all of its descendants, known or still orphans
+    pub fn mark_invalid(&mut self,hash:&H256){
+        self.invalid_blocks.insert(*hash);
+        for child in self.children_map.get(hash).cloned().unwrap_or_default(){
+            self.mark_invalid(&child);
         }
-        if valid {
-            self.block_state.insert(block.hash(), prev_state.clone());
+        for child in self.orphan_pool.take_children(hash){
+            self.mark_invalid(&child.hash());
         }
-        valid
+    }
+    pub fn is_invalid(&self,hash:&H256)->bool{
+        self.invalid_blocks.contains(hash)
+    }
+    pub fn config(&self)->&ChainConfig{
+        &self.config
+    }
+    /// The id of this chain, the hash of its genesis block, which commits to the chain config.
+    /// Transactions are signed for it, so they are not valid on a chain of another config.
+    pub fn chain_id(&self)->H256{
+        self.chain_id
     }
     /// Insert a block into blockchain
+    ///
+    /// The tip is the block with the most cumulative work. When two chains have the same work
+    /// the one inserted first stays the tip, so replaying the same blocks always gives the same tip.
     pub fn insert(&mut self, block: &Block) {
         let hash=block.hash();
         let parent=block.header.parent;
         let par_height=self.height_map.get(&parent).unwrap();
         let son_height=par_height+1;
+        let son_work=self.work_map[&parent].checked_add(&block.header.work()).unwrap();
         self.height_map.insert(hash, son_height);
+        self.work_map.insert(hash, son_work);
         self.chain_map.insert(hash,block.clone());
-        if son_height > *self.height_map.get(&self.hash_tip).unwrap()
+        self.children_map.entry(parent).or_default().push(hash);
+        self.persist(RecordKind::Block,block);
+        // a block whose transactions do not apply stays known but can never become the tip
+        if self.invalid_blocks.contains(&parent){
+            self.invalid_blocks.insert(hash);
+            return;
+        }
+        if son_work > self.work_map[&self.hash_tip]
         {
-            self.hash_tip=hash;
+            self.reorganize(hash);
         }
-        self.update_state(block);
+    }
+    /// Get the height of a known block, the genesis block being at height 0
+    pub fn get_height(&self,hash:&H256)->Option<u64>{
+        self.height_map.get(hash).copied()
+    }
+    pub fn tip_height(&self)->u64{
+        self.height_map[&self.hash_tip]
+    }
+    /// Get the cumulative work of the chain ending at a known block
+    pub fn chainwork(&self,hash:&H256)->Option<H256>{
+        self.work_map.get(hash).copied()
+    }
+    /// Get the hash of the block at height `height` of the longest chain
+    pub fn block_at_height(&self,height:u64)->Option<H256>{
+        self.main_chain.get(height as usize).copied()
     }
     pub fn parent_check(&self, block: &Block) -> bool {
         self.contain_block(&block.header.parent)
@@ -107,8 +395,19 @@ impl Blockchain {
     {
         self.chain_map.len()
     }
+    /// Buffer a block whose parent is unknown until the parent gets inserted
     pub fn add_to_orphans(&mut self,block:&Block){
-        self.orphan_buffer.entry(block.hash()).or_insert(vec![]).push(block.clone());
+        let now=SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        if self.orphan_pool.insert(block.clone(),now){
+            self.persist(RecordKind::Orphan,block);
+        }
+    }
+    pub fn contain_orphan(&self,hash:&H256)->bool{
+        self.orphan_pool.contains(hash)
+    }
+    /// Get the unknown blocks that buffered orphans are waiting for, so they can be requested again
+    pub fn missing_parents(&self)->Vec<H256>{
+        self.orphan_pool.missing_parents().into_iter().filter(|hash| !self.contain_block(hash)).collect()
     }
     pub fn all_block_delay(&self) -> Vec<u128>{
         let mut delay_vec:Vec<_>=self.hash_to_origin.values().filter_map(|blk|{
@@ -130,17 +429,67 @@ impl Blockchain {
     pub fn tip(&self) -> H256 {
         self.hash_tip
     }
-    pub fn get_block_state(&self,hash:&H256)->State{
-        self.block_state.get(hash).unwrap().clone()
+    pub fn tip_state(&self)->&dyn Ledger{
+        self.state.as_ref()
+    }
+    pub fn get_tip_state(&self)->Box<dyn Ledger>{
+        self.state.clone()
+    }
+    /// A transaction of the main chain and the hash of its block
+    pub fn get_transaction(&self,txid:&H256)->Option<(&LedgerTransaction,H256)>{
+        let (block,position)=self.tx_index.location(txid)?;
+        Some((&self.chain_map[&block].content.transactions[position],block))
     }
-    pub fn get_tip_state(&self)->State{
-        self.get_block_state(&self.hash_tip)
+    /// Up to `limit` transactions of the main chain whose memo starts with `prefix`, ordered by memo
+    pub fn transactions_by_memo(&self,prefix:&[u8],limit:usize)->Vec<(&LedgerTransaction,H256)>{
+        self.tx_index.search_memo(prefix,limit).iter().filter_map(|txid| self.get_transaction(txid)).collect()
     }
+    /// Check the proof of work. The target itself can only be checked once the parent is known.
     pub fn pow_validity_check(&self, block: &Block) -> bool {
-        block.hash() <= block.header.difficulty && block.header.difficulty == self.difficulty
+        let target=block.header.difficulty;
+        if block.hash() > target || target > self.config.pow_limit{
+            return false;
+        }
+        !self.contain_block(&block.header.parent) || target==self.expected_difficulty(&block.header.parent)
+    }
+    /// Get the target a child of `parent` has to meet.
+    ///
+    /// The target is kept for `retarget_window` blocks. At every multiple of the window (from the
+    /// second window on) it is scaled by how long the last `retarget_window` block intervals took
+    /// compared to `block_interval_ms * retarget_window`, by at most a factor of 4 either way,
+    /// and never above `pow_limit`.
+    pub fn expected_difficulty(&self,parent:&H256)->H256{
+        let parent_block=self.get_block(parent);
+        let target=parent_block.header.difficulty;
+        let window=self.config.retarget_window;
+        let height=self.height_map[parent]+1;
+        if window==0 || !height.is_multiple_of(window) || height<2*window{
+            return target;
+        }
+        let mut first=parent_block;
+        for _ in 0..window{
+            first=self.get_block(&first.header.parent);
+        }
+        let expected=self.config.block_interval_ms*window;
+        let actual=parent_block.header.timestamp.saturating_sub(first.header.timestamp);
+        let actual=(actual.min(u64::MAX as u128) as u64).clamp(expected/4,expected*4).max(1);
+        // reduce the ratio first so the multiplication is less likely to overflow
+        let (mut a,mut b)=(actual,expected);
+        while b!=0{
+            (a,b)=(b,a%b);
+        }
+        let (actual,expected)=(actual/a,expected/a);
+        let retarget=match target.checked_mul_u64(actual){
+            Some(scaled)=>scaled.checked_div(&H256::from(expected)),
+            None=>target.checked_div(&H256::from(expected)).and_then(|t| t.checked_mul_u64(actual)),
+        };
+        match retarget{
+            Some(retarget) if retarget<=self.config.pow_limit=>retarget,
+            _=>self.config.pow_limit,
+        }
     }
     pub fn contain_block(&self,hash:&H256) ->bool{
-        self.chain_map.contains_key(&hash).into()
+        self.chain_map.contains_key(hash)
     }   
     pub fn insert_all(&mut self,block:&Block,out_hash:&mut Vec<H256>){
         if self.chain_map.contains_key(&block.hash()){
@@ -148,9 +497,15 @@ impl Blockchain {
         }
         self.insert(block);
         out_hash.push(block.hash());
-        if self.orphan_buffer.contains_key(&block.hash()){
-            for child in self.orphan_buffer.remove(&block.hash()).unwrap(){
-                self.insert_all(&child,out_hash)
+        for child in self.orphan_pool.take_children(&block.hash()){
+            match self.validate_block(&child){
+                Ok(())=>self.insert_all(&child,out_hash),
+                Err(e)=>{
+                    info!("rejected orphan block {}: {}",child.hash(),e);
+                    if e.marks_invalid(){
+                        self.mark_invalid(&child.hash());
+                    }
+                }
             }
         }
     }
@@ -161,16 +516,9 @@ impl Blockchain {
     pub fn average_size(&self)->usize{
         self.chain_map.values().map(|block| block.size()).sum::<usize>()/self.block_size()
     }
-    /// Get the last block's hash of the longest chain
+    /// Get all the blocks' hashes of the longest chain, ordered from genesis to tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
-       let mut tail_hash=self.hash_tip;
-       let mut logest_chain_hashes=vec![tail_hash];
-       while *self.height_map.get(&tail_hash).unwrap()> 0
-       {
-            tail_hash=self.chain_map.get(&tail_hash).unwrap().header.parent;
-            logest_chain_hashes.push(tail_hash);
-       }
-       logest_chain_hashes.into_iter().rev().collect()
+        self.main_chain.clone()
     }
 }
 
@@ -179,7 +527,19 @@ impl Blockchain {
 mod tests {
     use super::*;
     use crate::basic::block::test::generate_random_block;
+    use crate::basic::amount::MAX_SUPPLY;
+    use crate::basic::block::{Coinbase, Content, Header};
     use crate::crypto::hash::Hashable;
+    use crate::crypto::hash::tests::generate_random_hash;
+    use crate::crypto::key_pair;
+    use crate::transaction::htlc::hashlock;
+    use crate::transaction::multisig::MultisigPolicy;
+    use crate::transaction::script::{Op, Script, ScriptError, ScriptWitness};
+    use crate::transaction::transaction::{signing_message, SignedTransaction, MAX_MEMO_SIZE, Transaction, TransactionError, TransactionKind};
+
+    fn amount(units:u64)->Amount{
+        Amount::from_base_units(units)
+    }
 
     #[test]
     fn insert_one() {
@@ -235,4 +595,935 @@ mod tests {
         assert_eq!(blockchain.tip(),block_5.hash());
     }
 
-}
\ No newline at end of file
+    fn temp_dir(name:&str)->std::path::PathBuf{
+        let dir=std::env::temp_dir().join(format!("bitcoin-{}-{}",name,rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reopen_restores_chain() {
+        let dir=temp_dir("reopen");
+        let mut blockchain = Blockchain::open(&dir,ChainConfig::default()).unwrap();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_2);
+        let fork_block_1 = generate_random_block(&block_1.hash());
+        blockchain.insert(&fork_block_1);
+        let fork_block_2 = generate_random_block(&fork_block_1.hash());
+        blockchain.insert(&fork_block_2);
+        let block_3 = generate_random_block(&block_2.hash());
+        blockchain.insert(&block_3);
+        let tip=blockchain.tip();
+        let longest_chain=blockchain.all_blocks_in_longest_chain();
+        let tip_accounts=accounts(blockchain.tip_state());
+        drop(blockchain);
+
+        let mut blockchain = Blockchain::open(&dir,ChainConfig::default()).unwrap();
+        assert_eq!(blockchain.tip(), tip);
+        assert_eq!(blockchain.tip(), fork_block_2.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), longest_chain);
+        assert_eq!(blockchain.block_size(), 6);
+        assert_eq!(accounts(blockchain.tip_state()), tip_accounts);
+        // blocks inserted after the reopen are persisted too
+        let block_4 = generate_random_block(&block_3.hash());
+        blockchain.insert(&block_4);
+        drop(blockchain);
+        let blockchain = Blockchain::open(&dir,ChainConfig::default()).unwrap();
+        assert_eq!(blockchain.tip(), block_4.hash());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopen_needs_the_same_config() {
+        let dir=temp_dir("config");
+        let blockchain = Blockchain::open(&dir,ChainConfig::default()).unwrap();
+        drop(blockchain);
+        let utxo=ChainConfig{ledger:LedgerModel::Utxo,..ChainConfig::default()};
+        let slower=ChainConfig{block_interval_ms:5000,..ChainConfig::default()};
+        for config in [utxo,slower]{
+            assert_eq!(Blockchain::open(&dir,config).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        }
+        assert!(Blockchain::open(&dir,ChainConfig::default()).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopen_discards_torn_append() {
+        use std::io::Write;
+        let dir=temp_dir("torn");
+        let mut blockchain = Blockchain::open(&dir,ChainConfig::default()).unwrap();
+        let block_1 = generate_random_block(&blockchain.tip());
+        blockchain.insert(&block_1);
+        drop(blockchain);
+        // a crash in the middle of an append leaves partial bytes behind
+        let mut blocks=std::fs::OpenOptions::new().append(true).open(dir.join("blocks.dat")).unwrap();
+        blocks.write_all(&[7u8;10]).unwrap();
+        let mut index=std::fs::OpenOptions::new().append(true).open(dir.join("index.dat")).unwrap();
+        index.write_all(&[7u8;20]).unwrap();
+        drop(blocks);
+        drop(index);
+
+        let mut blockchain = Blockchain::open(&dir,ChainConfig::default()).unwrap();
+        assert_eq!(blockchain.tip(), block_1.hash());
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_2);
+        drop(blockchain);
+        let blockchain = Blockchain::open(&dir,ChainConfig::default()).unwrap();
+        assert_eq!(blockchain.all_blocks_in_longest_chain().len(), 3);
+        assert_eq!(blockchain.tip(), block_2.hash());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopen_refuses_unreadable_records() {
+        let dir=temp_dir("unreadable");
+        let mut blockchain = Blockchain::open(&dir,ChainConfig::default()).unwrap();
+        let block_1 = generate_random_block(&blockchain.tip());
+        blockchain.insert(&block_1);
+        drop(blockchain);
+        // a complete record that does not decode is not a torn append, so nothing is truncated
+        let mut blocks=std::fs::read(dir.join("blocks.dat")).unwrap();
+        let size=blocks.len();
+        for byte in blocks[8..24].iter_mut(){
+            *byte^=0xff;
+        }
+        std::fs::write(dir.join("blocks.dat"),&blocks).unwrap();
+        assert_eq!(Blockchain::open(&dir,ChainConfig::default()).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        assert_eq!(std::fs::read(dir.join("blocks.dat")).unwrap().len(), size);
+        // nor is a store of another format version
+        let mut index=std::fs::read(dir.join("index.dat")).unwrap();
+        index[4..8].copy_from_slice(&(crate::blockchain::store::STORE_VERSION+1).to_le_bytes());
+        std::fs::write(dir.join("index.dat"),&index).unwrap();
+        assert_eq!(Blockchain::open(&dir,ChainConfig::default()).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        assert_eq!(std::fs::read(dir.join("index.dat")).unwrap(), index);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn long_chain_heights() {
+        let mut blockchain = Blockchain::new();
+        let mut hashes=vec![blockchain.tip()];
+        for _ in 0..3000 {
+            let block = generate_random_block(hashes.last().unwrap());
+            blockchain.insert(&block);
+            hashes.push(block.hash());
+        }
+        assert_eq!(blockchain.tip(), *hashes.last().unwrap());
+        assert_eq!(blockchain.tip_height(), 3000);
+        assert_eq!(blockchain.get_height(&hashes[300]), Some(300));
+        for (height,hash) in hashes.iter().enumerate() {
+            assert_eq!(blockchain.block_at_height(height as u64), Some(*hash));
+        }
+        assert_eq!(blockchain.block_at_height(3001), None);
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), hashes);
+    }
+
+    #[test]
+    fn block_at_height_follows_reorg() {
+        let mut blockchain = Blockchain::new();
+        let mut hashes=vec![blockchain.tip()];
+        for _ in 0..2000 {
+            let block = generate_random_block(hashes.last().unwrap());
+            blockchain.insert(&block);
+            hashes.push(block.hash());
+        }
+        // a competing branch forks off at height 1990 and overtakes at height 2001
+        let mut fork=hashes[..=1990].to_vec();
+        for _ in 0..11 {
+            let block = generate_random_block(fork.last().unwrap());
+            blockchain.insert(&block);
+            fork.push(block.hash());
+        }
+        assert_eq!(blockchain.tip(), *fork.last().unwrap());
+        assert_eq!(blockchain.tip_height(), 2001);
+        for (height,hash) in fork.iter().enumerate() {
+            assert_eq!(blockchain.block_at_height(height as u64), Some(*hash));
+        }
+        // and the original branch takes the lead back
+        for _ in 0..2 {
+            let block = generate_random_block(hashes.last().unwrap());
+            blockchain.insert(&block);
+            hashes.push(block.hash());
+        }
+        assert_eq!(blockchain.tip(), *hashes.last().unwrap());
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), hashes);
+        assert_eq!(blockchain.block_at_height(2002), Some(hashes[2002]));
+    }
+
+    #[test]
+    fn heaviest_chain_wins() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        assert_eq!(block_1.header.work(), H256::from(255u64));
+        blockchain.insert(&block_1);
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_2);
+        let block_3 = generate_random_block(&block_2.hash());
+        blockchain.insert(&block_3);
+        assert_eq!(blockchain.tip(), block_3.hash());
+        // a single block with a 256 times harder target outweighs the three easy ones
+        let mut fork_block = generate_random_block(&genesis_hash);
+        let mut difficulty = [0u8; 32];
+        difficulty[1] = 1;
+        fork_block.header.difficulty = difficulty.into();
+        blockchain.insert(&fork_block);
+        assert_eq!(blockchain.tip(), fork_block.hash());
+        assert_eq!(blockchain.tip_height(), 1);
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![genesis_hash, fork_block.hash()]);
+        let genesis_work = blockchain.chainwork(&genesis_hash).unwrap();
+        assert_eq!(blockchain.chainwork(&fork_block.hash()), genesis_work.checked_add(&H256::from(65535u64)));
+    }
+
+    #[test]
+    fn difficulty_retargets_every_window() {
+        let config=ChainConfig{
+            block_interval_ms:1000,
+            retarget_window:4,
+            ..ChainConfig::default()
+        };
+        let pow_limit=config.pow_limit;
+        let mut blockchain = Blockchain::with_config(config);
+        let mut timestamp=1_000_000u128;
+        let mut extend=|blockchain:&mut Blockchain,count:usize,spacing:u128|{
+            for _ in 0..count {
+                let parent=blockchain.tip();
+                let mut block = generate_random_block(&parent);
+                timestamp+=spacing;
+                block.header.timestamp=timestamp;
+                block.header.difficulty=blockchain.expected_difficulty(&parent);
+                blockchain.insert(&block);
+            }
+        };
+        // no retarget before the second window
+        extend(&mut blockchain,3,500);
+        assert_eq!(blockchain.expected_difficulty(&blockchain.tip()), pow_limit);
+        // heights 4..=7 came twice as fast as wanted, so height 8 gets half the target
+        extend(&mut blockchain,4,500);
+        let halved=pow_limit.checked_div(&H256::from(2u64)).unwrap();
+        assert_eq!(blockchain.expected_difficulty(&blockchain.tip()), halved);
+        extend(&mut blockchain,1,500);
+        assert_eq!(blockchain.get_block(&blockchain.tip()).header.difficulty, halved);
+        // the target holds for the rest of the window
+        extend(&mut blockchain,3,1000);
+        assert_eq!(blockchain.get_block(&blockchain.tip()).header.difficulty, halved);
+        // heights 8..=11 took 500 + 3 * 1000 ms against 4000 ms wanted
+        let eased=halved.checked_mul_u64(7).unwrap().checked_div(&H256::from(8u64)).unwrap();
+        extend(&mut blockchain,1,1);
+        assert_eq!(blockchain.get_block(&blockchain.tip()).header.difficulty, eased);
+        // a very fast window is clamped to a factor of 4
+        extend(&mut blockchain,4,1);
+        let clamped=eased.checked_div(&H256::from(4u64)).unwrap();
+        assert_eq!(blockchain.get_block(&blockchain.tip()).header.difficulty, clamped);
+        // and a very slow one never goes above the limit
+        extend(&mut blockchain,3,1_000_000);
+        assert_eq!(blockchain.expected_difficulty(&blockchain.tip()), clamped.checked_mul_u64(4).unwrap());
+        extend(&mut blockchain,4,1_000_000);
+        assert_eq!(blockchain.expected_difficulty(&blockchain.tip()), pow_limit);
+    }
+
+    fn signed_transaction(sender:u8,receiver:u8,nonce:usize,value:u64)->LedgerTransaction{
+        signed_transaction_with_fee(sender,receiver,nonce,value,0)
+    }
+
+    fn signed_transaction_with_fee(sender:u8,receiver:u8,nonce:usize,value:u64,fee:u64)->LedgerTransaction{
+        SignedTransaction::from_raw(raw_transaction(sender,receiver,nonce,value,fee),&Block::genesis().hash(),&ico_key_pair(sender)).into()
+    }
+
+    /// A transfer signed for the chain of `blockchain`, for chains of another config than the default
+    fn signed_on(blockchain:&Blockchain,sender:u8,receiver:u8,nonce:usize,value:u64,fee:u64)->LedgerTransaction{
+        SignedTransaction::from_raw(raw_transaction(sender,receiver,nonce,value,fee),&blockchain.chain_id(),&ico_key_pair(sender)).into()
+    }
+
+    fn raw_transaction(sender:u8,receiver:u8,nonce:usize,value:u64,fee:u64)->Transaction{
+        Transaction{
+            sender:ico_address(sender),
+            receiver:ico_address(receiver),
+            nonce,
+            value:amount(value),
+            fee:amount(fee),
+            valid_until_height:None,
+            kind:TransactionKind::Transfer,
+            memo:Vec::new(),
+        }
+    }
+
+    /// Build a block on `parent` that passes the proof of work check.
+    /// Tests using it stay within the first retarget window, where the target is the limit.
+    fn mine_block(blockchain:&Blockchain,parent:&H256,transactions:Vec<LedgerTransaction>)->Block{
+        mine_block_with_coinbase(blockchain,parent,None,transactions)
+    }
+
+    fn mine_block_with_coinbase(blockchain:&Blockchain,parent:&H256,coinbase:Option<Coinbase>,transactions:Vec<LedgerTransaction>)->Block{
+        let content=Content{coinbase,transactions};
+        // blocks mined within the same millisecond still have to move past the median time
+        let mut timestamp=SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        if blockchain.contain_block(parent){
+            timestamp=timestamp.max(blockchain.median_time_past(parent)+1);
+        }
+        // a random start keeps blocks with the same parent and content apart
+        let mut block=Block{
+            header:Header{
+                parent:*parent,
+                nonce:rand::random(),
+                difficulty:blockchain.config().pow_limit,
+                timestamp,
+                merkle_root:content.merkle_root(),
+            },
+            content,
+        };
+        while block.hash()>block.header.difficulty{
+            block.header.nonce=block.header.nonce.wrapping_add(1);
+        }
+        block
+    }
+
+    #[test]
+    fn validate_block_rejections() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block = mine_block(&blockchain,&genesis_hash,vec![signed_transaction(1,2,1,10)]);
+        let before = accounts(blockchain.tip_state());
+        assert_eq!(blockchain.validate_block(&block), Ok(()));
+        // validating tries the block on the tip state and leaves it as it was
+        assert_eq!(accounts(blockchain.tip_state()), before);
+
+        let mut bad_root = block.clone();
+        bad_root.header.merkle_root = H256::default();
+        assert_eq!(blockchain.validate_block(&bad_root), Err(BlockError::BadMerkleRoot));
+
+        let mut forged = SignedTransaction::from_raw(raw_transaction(1,2,1,10,0),&genesis_hash,&ico_key_pair(1));
+        forged.trans_raw.value = amount(900);
+        let bad_signature = mine_block(&blockchain,&genesis_hash,vec![forged.into()]);
+        assert_eq!(blockchain.validate_block(&bad_signature),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::BadSignature}));
+
+        let raw = raw_transaction(1,2,1,10,0);
+        let other_chain = mine_block(&blockchain,&genesis_hash,vec![SignedTransaction::from_raw(raw,&generate_random_hash(),&ico_key_pair(1)).into()]);
+        assert_eq!(blockchain.validate_block(&other_chain),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::BadSignature}));
+
+        // a valid signature, but by a key that does not own the sender
+        let raw = raw_transaction(1,2,1,10,0);
+        let not_owner = mine_block(&blockchain,&genesis_hash,vec![SignedTransaction::from_raw(raw,&genesis_hash,&key_pair::random()).into()]);
+        assert_eq!(blockchain.validate_block(&not_owner),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::NotOwner}));
+
+        let bad_nonce = mine_block(&blockchain,&genesis_hash,vec![signed_transaction(1,2,1,10),signed_transaction(1,3,1,10)]);
+        assert_eq!(blockchain.validate_block(&bad_nonce),
+            Err(BlockError::InvalidTransaction{index:1,error:TransactionError::BadNonce{expected:2,got:1}}));
+
+        let overspend = mine_block(&blockchain,&genesis_hash,vec![signed_transaction_with_fee(1,2,1,999,2)]);
+        assert_eq!(blockchain.validate_block(&overspend),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::InsufficientBalance{balance:amount(1000),cost:amount(1001)}}));
+
+        // the block is at height 1, after the transaction's last height
+        let mut raw = raw_transaction(1,2,1,10,0);
+        raw.valid_until_height = Some(0);
+        let expired = mine_block(&blockchain,&genesis_hash,vec![SignedTransaction::from_raw(raw,&Block::genesis().hash(),&ico_key_pair(1)).into()]);
+        assert_eq!(blockchain.validate_block(&expired),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::Expired{valid_until:0,height:1}}));
+
+        let beyond_supply = mine_block(&blockchain,&genesis_hash,vec![signed_transaction_with_fee(1,2,1,MAX_SUPPLY.base_units(),1)]);
+        assert_eq!(blockchain.validate_block(&beyond_supply),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::Overflow}));
+
+        let mut too_early = block.clone();
+        too_early.header.timestamp = 0;
+        while too_early.hash()>too_early.header.difficulty{
+            too_early.header.nonce+=1;
+        }
+        assert_eq!(blockchain.validate_block(&too_early), Err(BlockError::TimestampTooEarly));
+
+        let mut too_late = block.clone();
+        too_late.header.timestamp += 24*60*60*1000;
+        while too_late.hash()>too_late.header.difficulty{
+            too_late.header.nonce+=1;
+        }
+        assert_eq!(blockchain.validate_block(&too_late), Err(BlockError::TimestampInFuture));
+
+        let mut no_work = block.clone();
+        no_work.header.difficulty = H256::default();
+        assert_eq!(blockchain.validate_block(&no_work), Err(BlockError::BadProofOfWork));
+
+        let mut small = Blockchain::with_config(ChainConfig{max_block_size:100,..ChainConfig::default()});
+        assert_eq!(small.validate_block(&block), Err(BlockError::TooLarge{size:block.size()}));
+    }
+
+    #[test]
+    fn relayed_copies_do_not_condemn_the_block() {
+        // the size of a block of three transfers does not depend on the chain it is for
+        let transfers = |blockchain:&Blockchain| vec![signed_on(blockchain,1,2,1,10,0),signed_on(blockchain,2,3,1,10,0),signed_on(blockchain,3,4,1,10,0)];
+        let default = Blockchain::new();
+        let size = mine_block(&default,&default.tip(),transfers(&default)).size();
+        let mut blockchain = Blockchain::with_config(ChainConfig{max_block_size:size,..ChainConfig::default()});
+        let genesis_hash = blockchain.tip();
+        let block = mine_block(&blockchain,&genesis_hash,transfers(&blockchain));
+        assert_eq!(block.size(), size);
+        let mut padded = block.clone();
+        padded.content.transactions.push(signed_on(&blockchain,4,5,1,10,0));
+        // three transactions hash to the same root as the last one repeated
+        let mut repeated = block.clone();
+        repeated.content.transactions.push(signed_on(&blockchain,3,4,1,10,0));
+        assert_eq!(repeated.content.merkle_root(), block.header.merkle_root);
+        for (copy,error) in [(padded,BlockError::BadMerkleRoot),(repeated,BlockError::DuplicateTransaction)]{
+            assert_eq!(copy.hash(), block.hash());
+            assert_eq!(blockchain.validate_block(&copy), Err(error.clone()));
+            assert!(!error.marks_invalid());
+        }
+        assert!(!BlockError::TooLarge{size:block.size()+1}.marks_invalid());
+        blockchain.insert(&block);
+        assert_eq!(blockchain.tip(), block.hash());
+    }
+
+    #[test]
+    fn invalid_blocks_never_become_tip() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        // the fork is longer, but its first block spends more than the account holds
+        let bad_block = mine_block(&blockchain,&genesis_hash,vec![signed_transaction(1,2,1,5000)]);
+        blockchain.insert(&bad_block);
+        let child = generate_random_block(&bad_block.hash());
+        blockchain.insert(&child);
+        assert_eq!(blockchain.tip(), block_1.hash());
+        assert!(blockchain.is_invalid(&bad_block.hash()));
+        assert!(blockchain.is_invalid(&child.hash()));
+        let grandchild = generate_random_block(&child.hash());
+        assert_eq!(blockchain.validate_block(&grandchild), Err(BlockError::InvalidAncestor));
+    }
+
+    #[test]
+    fn orphans_connect_when_parent_arrives() {
+        let dir=temp_dir("orphans");
+        let mut blockchain = Blockchain::open(&dir,ChainConfig::default()).unwrap();
+        let genesis_hash = blockchain.tip();
+        let block_1 = mine_block(&blockchain,&genesis_hash,vec![]);
+        let mut parent_chain = Blockchain::new();
+        parent_chain.insert(&block_1);
+        let block_2 = mine_block(&parent_chain,&block_1.hash(),vec![]);
+        parent_chain.insert(&block_2);
+        let block_3 = mine_block(&parent_chain,&block_2.hash(),vec![]);
+        blockchain.add_to_orphans(&block_3);
+        blockchain.add_to_orphans(&block_2);
+        assert_eq!(blockchain.missing_parents(), vec![block_1.hash()]);
+        drop(blockchain);
+
+        // the orphans survive a restart and connect once their parent shows up
+        let mut blockchain = Blockchain::open(&dir,ChainConfig::default()).unwrap();
+        assert!(blockchain.contain_orphan(&block_2.hash()));
+        assert_eq!(blockchain.missing_parents(), vec![block_1.hash()]);
+        let mut connected=Vec::new();
+        blockchain.insert_all(&block_1,&mut connected);
+        assert_eq!(connected, vec![block_1.hash(),block_2.hash(),block_3.hash()]);
+        assert_eq!(blockchain.tip(), block_3.hash());
+        assert!(blockchain.missing_parents().is_empty());
+        assert!(!blockchain.contain_orphan(&block_2.hash()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Build a branch of blocks on `parent`, one per list of transactions
+    fn build_branch(blockchain:&Blockchain,parent:&H256,blocks:Vec<Vec<LedgerTransaction>>)->Vec<Block>{
+        let mut parent=*parent;
+        let mut branch=Vec::new();
+        for transactions in blocks{
+            let block=mine_block(blockchain,&parent,transactions);
+            parent=block.hash();
+            branch.push(block);
+        }
+        branch
+    }
+
+    /// The accounts of the genesis keys, which are the only ones the tests use
+    fn accounts(state:&dyn Ledger)->Vec<Option<(usize,Amount)>>{
+        (0..10).map(|i| state.account(&ico_address(i))).collect()
+    }
+
+    /// The tip state of a fresh chain that only ever saw `blocks`
+    fn replayed_accounts(blocks:&[Block])->Vec<Option<(usize,Amount)>>{
+        let mut blockchain = Blockchain::new();
+        for block in blocks{
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.tip(), blocks.last().unwrap().hash());
+        accounts(blockchain.tip_state())
+    }
+
+    #[test]
+    fn reorg_state_matches_replay() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut branch_a = build_branch(&blockchain,&genesis_hash,vec![
+            vec![signed_transaction(1,2,1,100)],
+            vec![signed_transaction(2,3,1,50),signed_transaction(1,6,2,10)],
+        ]);
+        let branch_b = build_branch(&blockchain,&genesis_hash,vec![
+            vec![signed_transaction(1,4,1,300)],
+            vec![signed_transaction(4,5,1,100),signed_transaction(4,7,2,1)],
+            vec![],
+        ]);
+        for block in branch_a.iter().chain(branch_b[..2].iter()){
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.tip(), branch_a[1].hash());
+        assert_eq!(accounts(blockchain.tip_state()), replayed_accounts(&branch_a));
+        blockchain.insert(&branch_b[2]);
+        assert_eq!(blockchain.tip(), branch_b[2].hash());
+        assert_eq!(accounts(blockchain.tip_state()), replayed_accounts(&branch_b));
+        // and back again
+        let tail = build_branch(&blockchain,&branch_a[1].hash(),vec![vec![signed_transaction(3,1,1,20)],vec![]]);
+        for block in tail.iter(){
+            blockchain.insert(block);
+        }
+        branch_a.extend(tail);
+        assert_eq!(blockchain.tip(), branch_a[3].hash());
+        assert_eq!(accounts(blockchain.tip_state()), replayed_accounts(&branch_a));
+        assert_eq!(blockchain.all_blocks_in_longest_chain()[1..].to_vec(), branch_a.iter().map(|block| block.hash()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn invalid_branch_restores_old_chain() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let branch_a = build_branch(&blockchain,&genesis_hash,vec![vec![signed_transaction(1,2,1,100)],vec![]]);
+        let branch_b = build_branch(&blockchain,&genesis_hash,vec![
+            vec![signed_transaction(1,3,1,700)],
+            vec![signed_transaction(1,3,2,700)],
+            vec![],
+        ]);
+        for block in branch_a.iter().chain(branch_b.iter()){
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.tip(), branch_a[1].hash());
+        assert_eq!(accounts(blockchain.tip_state()), replayed_accounts(&branch_a));
+        assert!(!blockchain.is_invalid(&branch_b[0].hash()));
+        assert!(blockchain.is_invalid(&branch_b[1].hash()));
+        assert!(blockchain.is_invalid(&branch_b[2].hash()));
+    }
+
+    #[test]
+    fn reorg_depth_is_limited() {
+        let config=ChainConfig{max_reorg_depth:3,..ChainConfig::default()};
+        let mut blockchain = Blockchain::with_config(config);
+        let genesis_hash = blockchain.tip();
+        let main = build_branch(&blockchain,&genesis_hash,vec![vec![];5]);
+        for block in main.iter(){
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.undo_map.len(), 3);
+        // rolling back 5 blocks is too deep, even for a longer chain
+        let deep = build_branch(&blockchain,&genesis_hash,vec![vec![];6]);
+        for block in deep.iter(){
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.tip(), main[4].hash());
+        // rolling back 2 blocks is fine
+        let shallow = build_branch(&blockchain,&main[2].hash(),vec![vec![];3]);
+        for block in shallow.iter(){
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.tip(), shallow[2].hash());
+        assert_eq!(blockchain.undo_map.len(), 3);
+    }
+
+    #[test]
+    fn reorg_is_announced() {
+        let mut blockchain = Blockchain::new();
+        let events = blockchain.subscribe();
+        let genesis_hash = blockchain.tip();
+        let branch_a = build_branch(&blockchain,&genesis_hash,vec![vec![];2]);
+        let branch_b = build_branch(&blockchain,&genesis_hash,vec![vec![];3]);
+        for block in branch_a.iter(){
+            blockchain.insert(block);
+        }
+        assert_eq!(events.try_recv().unwrap(), ReorgEvent{disconnected:vec![],connected:vec![branch_a[0].hash()]});
+        assert_eq!(events.try_recv().unwrap(), ReorgEvent{disconnected:vec![],connected:vec![branch_a[1].hash()]});
+        for block in branch_b.iter(){
+            blockchain.insert(block);
+        }
+        assert_eq!(events.try_recv().unwrap(), ReorgEvent{
+            disconnected:vec![branch_a[1].hash(),branch_a[0].hash()],
+            connected:branch_b.iter().map(|block| block.hash()).collect(),
+        });
+        assert!(events.try_recv().is_err());
+        drop(events);
+        blockchain.insert(&mine_block(&blockchain,&branch_b[2].hash(),vec![]));
+        assert!(blockchain.subscribers.is_empty());
+    }
+
+    #[test]
+    fn coinbase_rewards_mature() {
+        let config=ChainConfig{coinbase_maturity:2,..ChainConfig::default()};
+        let mut blockchain = Blockchain::with_config(config);
+        let genesis_hash = blockchain.tip();
+        let miner = ico_address(7);
+        let reward = |height| Some(Coinbase{height,miner,value:amount(50)});
+        let block_1 = mine_block_with_coinbase(&blockchain,&genesis_hash,reward(1),vec![]);
+        blockchain.insert(&block_1);
+        assert!(blockchain.tip_state().account(&miner).is_none());
+        assert_eq!(blockchain.tip_state().immature_rewards().len(), 1);
+
+        // the reward of block 1 cannot be spent in block 2
+        let spend = signed_on(&blockchain,7,9,1,50,0);
+        let early = mine_block_with_coinbase(&blockchain,&block_1.hash(),reward(2),vec![spend.clone()]);
+        assert_eq!(blockchain.validate_block(&early),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::UnknownSender}));
+        let block_2 = mine_block_with_coinbase(&blockchain,&block_1.hash(),reward(2),vec![]);
+        blockchain.insert(&block_2);
+        // but it can be in block 3
+        let block_3 = mine_block_with_coinbase(&blockchain,&block_2.hash(),reward(3),vec![spend]);
+        assert_eq!(blockchain.validate_block(&block_3), Ok(()));
+        blockchain.insert(&block_3);
+        assert_eq!(blockchain.tip(), block_3.hash());
+        assert_eq!(blockchain.tip_state().account(&miner).unwrap(), (1,amount(0)));
+        assert_eq!(blockchain.tip_state().account(&ico_address(9)).unwrap(), (0,amount(50)));
+        assert_eq!(blockchain.tip_state().immature_rewards().keys().copied().collect::<Vec<u64>>(), vec![2,3]);
+
+        // a reorg takes the rewards back
+        let branch = build_branch(&blockchain,&genesis_hash,vec![vec![];4]);
+        for block in branch.iter(){
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.tip(), branch[3].hash());
+        assert!(blockchain.tip_state().account(&miner).is_none());
+        assert!(blockchain.tip_state().immature_rewards().is_empty());
+    }
+
+    #[test]
+    fn coinbase_claims_are_checked() {
+        let config=ChainConfig{halving_interval:2,..ChainConfig::default()};
+        let subsidies:Vec<u64>=(0..6).map(|height| config.block_subsidy(height).base_units()).collect();
+        assert_eq!(subsidies, vec![50,50,25,25,12,12]);
+        assert_eq!(config.block_subsidy(1000), Amount::ZERO);
+        assert_eq!(ChainConfig{halving_interval:0,..ChainConfig::default()}.block_subsidy(u64::MAX), amount(50));
+        let mut blockchain = Blockchain::with_config(config);
+        let genesis_hash = blockchain.tip();
+        let miner = ico_address(7);
+        let greedy = mine_block_with_coinbase(&blockchain,&genesis_hash,Some(Coinbase{height:1,miner,value:amount(51)}),vec![]);
+        assert_eq!(blockchain.validate_block(&greedy), Err(BlockError::ExcessiveCoinbase{claimed:amount(51),allowed:amount(50)}));
+        let wrong_height = mine_block_with_coinbase(&blockchain,&genesis_hash,Some(Coinbase{height:2,miner,value:amount(25)}),vec![]);
+        assert_eq!(blockchain.validate_block(&wrong_height), Err(BlockError::BadCoinbaseHeight));
+
+        let chain = build_branch(&blockchain,&genesis_hash,vec![vec![];2]);
+        for block in chain.iter(){
+            blockchain.insert(block);
+        }
+        let halved = mine_block_with_coinbase(&blockchain,&chain[1].hash(),Some(Coinbase{height:3,miner,value:amount(26)}),vec![]);
+        assert_eq!(blockchain.validate_block(&halved), Err(BlockError::ExcessiveCoinbase{claimed:amount(26),allowed:amount(25)}));
+        // a block claiming too much is never connected
+        blockchain.insert(&halved);
+        assert_eq!(blockchain.tip(), chain[1].hash());
+        assert!(blockchain.is_invalid(&halved.hash()));
+
+        // the fees can be claimed too, and leave the sender
+        let transactions = vec![signed_on(&blockchain,1,2,1,100,3),signed_on(&blockchain,1,2,2,100,4)];
+        let with_fees = mine_block_with_coinbase(&blockchain,&chain[1].hash(),Some(Coinbase{height:3,miner,value:amount(33)}),transactions.clone());
+        assert_eq!(blockchain.validate_block(&with_fees), Err(BlockError::ExcessiveCoinbase{claimed:amount(33),allowed:amount(32)}));
+        let with_fees = mine_block_with_coinbase(&blockchain,&chain[1].hash(),Some(Coinbase{height:3,miner,value:amount(32)}),transactions);
+        blockchain.insert(&with_fees);
+        assert_eq!(blockchain.tip(), with_fees.hash());
+        assert_eq!(blockchain.tip_state().account(&ico_address(1)).unwrap(), (2,amount(793)));
+        assert_eq!(blockchain.tip_state().immature_rewards()[&3], (miner,amount(32)));
+    }
+
+    /// A UTXO transaction spending genesis output `i-1` of account `i` on `blockchain`,
+    /// paying `value` to account `receiver` and the rest less `fee` back
+    #[test]
+    fn configs_have_chain_ids_of_their_own() {
+        let mut blockchain = Blockchain::new();
+        let mut slower = Blockchain::with_config(ChainConfig{block_interval_ms:5000,..ChainConfig::default()});
+        assert_eq!(blockchain.chain_id(), Block::genesis().hash());
+        assert_ne!(blockchain.chain_id(), slower.chain_id());
+        // a transaction signed for one deployment is refused by the other
+        let signed_for = |chain:&Blockchain| signed_on(chain,1,2,1,10,0);
+        let own = mine_block(&blockchain,&blockchain.tip(),vec![signed_for(&blockchain)]);
+        let foreign = mine_block(&blockchain,&blockchain.tip(),vec![signed_for(&slower)]);
+        assert_eq!(blockchain.validate_block(&own), Ok(()));
+        assert_eq!(blockchain.validate_block(&foreign),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::BadSignature}));
+        let own = mine_block(&slower,&slower.tip(),vec![signed_for(&slower)]);
+        let foreign = mine_block(&slower,&slower.tip(),vec![signed_for(&blockchain)]);
+        assert_eq!(slower.validate_block(&own), Ok(()));
+        assert_eq!(slower.validate_block(&foreign),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::BadSignature}));
+    }
+
+    fn utxo_spend(blockchain:&Blockchain,i:u8,receiver:u8,value:u64,fee:u64)->LedgerTransaction{
+        let outputs=vec![
+            TxOutput{value:amount(value),recipient:ico_address(receiver)},
+            TxOutput{value:amount(1000*i as u64-value-fee),recipient:ico_address(i)},
+        ];
+        let mut transaction=UtxoTransaction::new(vec![ico_transaction().outpoint(i as usize-1)],outputs,amount(fee));
+        transaction.sign_input(0,&blockchain.chain_id(),&ico_key_pair(i));
+        transaction.into()
+    }
+
+    #[test]
+    fn utxo_chain_spends_outputs() {
+        let mut blockchain = Blockchain::with_config(ChainConfig{ledger:LedgerModel::Utxo,..ChainConfig::default()});
+        let genesis_hash = blockchain.tip();
+        assert_ne!(genesis_hash, Block::genesis().hash());
+        assert_eq!(blockchain.tip_state().outputs_of(&ico_address(2)).len(), 1);
+        assert!(blockchain.tip_state().account(&ico_address(1)).is_none());
+
+        let spend = utxo_spend(&blockchain,1,2,300,5);
+        let block = mine_block(&blockchain,&genesis_hash,vec![spend.clone()]);
+        let double_spend = mine_block(&blockchain,&block.hash(),vec![utxo_spend(&blockchain,1,3,300,5)]);
+        blockchain.insert(&block);
+        assert_eq!(blockchain.tip(), block.hash());
+        assert_eq!(blockchain.tip_state().outputs_of(&ico_address(2)).len(), 2);
+        assert_eq!(blockchain.tip_state().outputs_of(&ico_address(1))[0].1.value, amount(695));
+        assert_eq!(blockchain.validate_block(&double_spend),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::MissingInput{index:0}}));
+
+        let account = SignedTransaction::from_raw(raw_transaction(2,3,1,10,0),&blockchain.chain_id(),&ico_key_pair(2));
+        let account = mine_block(&blockchain,&block.hash(),vec![account.into()]);
+        assert_eq!(blockchain.validate_block(&account),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::WrongLedger}));
+        // a signature for the account chain does not carry over
+        let mut transaction = UtxoTransaction::new(vec![ico_transaction().outpoint(1)],vec![],amount(2000));
+        transaction.sign_input(0,&Block::genesis().hash(),&ico_key_pair(2));
+        let other_chain = mine_block(&blockchain,&block.hash(),vec![transaction.into()]);
+        assert_eq!(blockchain.validate_block(&other_chain),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::BadSignature}));
+
+        // the spent outputs come back when the block is reorganized away
+        let fork = build_branch(&blockchain,&genesis_hash,vec![vec![],vec![]]);
+        for block in fork.iter(){
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.tip(), fork[1].hash());
+        assert_eq!(blockchain.tip_state().outputs_of(&ico_address(1)), vec![(ico_transaction().outpoint(0),TxOutput{value:amount(1000),recipient:ico_address(1)})]);
+    }
+
+    #[test]
+    fn multisig_needs_the_threshold() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let keys:Vec<u8>=vec![2,3,4];
+        let policy = MultisigPolicy::new(2,keys.iter().map(|i| ico_key_pair(*i).public_key().as_ref().to_vec()).collect()).unwrap();
+        let shared = policy.address();
+        let mut funding = raw_transaction(1,0,1,500,0);
+        funding.receiver = shared;
+        let funding = SignedTransaction::from_raw(funding,&genesis_hash,&ico_key_pair(1));
+        let block = mine_block(&blockchain,&genesis_hash,vec![funding.into()]);
+        blockchain.insert(&block);
+        assert_eq!(blockchain.tip_state().account(&shared), Some((0,amount(500))));
+
+        let mut raw = raw_transaction(1,5,1,200,0);
+        raw.sender = shared;
+        let mut transaction = SignedTransaction::multisig(raw.clone(),policy.clone());
+        assert!(transaction.sign_multisig(&genesis_hash,&ico_key_pair(3)));
+        assert!(!transaction.sign_multisig(&genesis_hash,&ico_key_pair(1)));
+        let one_signature = mine_block(&blockchain,&block.hash(),vec![transaction.clone().into()]);
+        assert_eq!(blockchain.validate_block(&one_signature),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::BadSignature}));
+        assert!(transaction.sign_multisig(&genesis_hash,&ico_key_pair(4)));
+        let two_signatures = mine_block(&blockchain,&block.hash(),vec![transaction.into()]);
+        assert_eq!(blockchain.validate_block(&two_signatures), Ok(()));
+
+        // the same keys with another threshold are another address
+        let mut lower = SignedTransaction::multisig(raw,MultisigPolicy::new(1,policy.pub_keys.clone()).unwrap());
+        lower.sign_multisig(&genesis_hash,&ico_key_pair(2));
+        let not_owner = mine_block(&blockchain,&block.hash(),vec![lower.into()]);
+        assert_eq!(blockchain.validate_block(&not_owner),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::NotOwner}));
+
+        blockchain.insert(&two_signatures);
+        assert_eq!(blockchain.tip_state().account(&shared), Some((1,amount(300))));
+        assert_eq!(blockchain.tip_state().account(&ico_address(5)), Some((0,amount(5200))));
+    }
+
+    fn transaction_of_kind(sender:u8,receiver:u8,nonce:usize,value:u64,kind:TransactionKind)->LedgerTransaction{
+        let raw=Transaction{kind,..raw_transaction(sender,receiver,nonce,value,0)};
+        SignedTransaction::from_raw(raw,&Block::genesis().hash(),&ico_key_pair(sender)).into()
+    }
+
+    #[test]
+    fn htlc_claim_and_refund() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let secret = b"secret".to_vec();
+        let lock = |nonce,timeout| transaction_of_kind(1,2,nonce,300,TransactionKind::Lock{hashlock:hashlock(&secret),timeout});
+        let (swap,expiring) = (lock(1,2),lock(2,1));
+        let escrow_id = |transaction:&LedgerTransaction| transaction.as_account().unwrap().trans_raw.hash();
+        let (swap_id,expiring_id) = (escrow_id(&swap),escrow_id(&expiring));
+        let block_1 = mine_block(&blockchain,&genesis_hash,vec![swap,expiring]);
+        blockchain.insert(&block_1);
+        assert_eq!(blockchain.tip_state().account(&ico_address(1)), Some((2,amount(400))));
+        assert_eq!(blockchain.tip_state().escrow(&swap_id).map(|escrow| escrow.value), Some(amount(300)));
+
+        let claim = |sender,escrow,preimage:&[u8]| transaction_of_kind(sender,0,1,0,TransactionKind::Claim{escrow,preimage:preimage.to_vec()});
+        let rejected = [
+            (claim(3,swap_id,&secret),TransactionError::NotEscrowParty),
+            (claim(2,swap_id,b"guess"),TransactionError::BadPreimage),
+            (claim(2,expiring_id,&secret),TransactionError::EscrowTimedOut{timeout:1,height:2}),
+            (claim(2,H256::default(),&secret),TransactionError::UnknownEscrow),
+            (transaction_of_kind(1,0,3,0,TransactionKind::Refund{escrow:swap_id}),TransactionError::EscrowLocked{timeout:2,height:2}),
+        ];
+        for (transaction,error) in rejected.iter(){
+            let block = mine_block(&blockchain,&block_1.hash(),vec![transaction.clone()]);
+            assert_eq!(blockchain.validate_block(&block), Err(BlockError::InvalidTransaction{index:0,error:error.clone()}));
+        }
+
+        let refund = transaction_of_kind(1,0,3,0,TransactionKind::Refund{escrow:expiring_id});
+        let block_2 = mine_block(&blockchain,&block_1.hash(),vec![claim(2,swap_id,&secret),refund]);
+        blockchain.insert(&block_2);
+        assert_eq!(blockchain.tip(), block_2.hash());
+        assert_eq!(blockchain.tip_state().account(&ico_address(1)), Some((3,amount(700))));
+        assert_eq!(blockchain.tip_state().account(&ico_address(2)), Some((1,amount(2300))));
+        assert!(blockchain.tip_state().escrow(&swap_id).is_none());
+        assert!(blockchain.tip_state().escrow(&expiring_id).is_none());
+    }
+
+    #[test]
+    fn script_accounts_run_their_script() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        // spendable by key 2, from height 3 on
+        let key = ico_key_pair(2);
+        let script = Script(vec![Op::Push(key.public_key().as_ref().to_vec()),Op::CheckSig,Op::number(3),Op::CheckHeight,Op::And]);
+        let locked = script.address();
+        let mut funding = raw_transaction(1,0,1,500,0);
+        funding.receiver = locked;
+        let block_1 = mine_block(&blockchain,&genesis_hash,vec![SignedTransaction::from_raw(funding,&genesis_hash,&ico_key_pair(1)).into()]);
+        blockchain.insert(&block_1);
+
+        let raw = Transaction{sender:locked,..raw_transaction(1,5,1,100,0)};
+        let scripted = |chain_id:&H256| {
+            let signature = key.sign(&signing_message(&raw,chain_id)).as_ref().to_vec();
+            SignedTransaction::scripted(raw.clone(),ScriptWitness{script:script.clone(),args:vec![signature]}).into()
+        };
+        let too_early = mine_block(&blockchain,&block_1.hash(),vec![scripted(&genesis_hash)]);
+        assert_eq!(blockchain.validate_block(&too_early),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::Script(ScriptError::False)}));
+        let block_2 = mine_block(&blockchain,&block_1.hash(),vec![]);
+        blockchain.insert(&block_2);
+        let other_chain = mine_block(&blockchain,&block_2.hash(),vec![scripted(&generate_random_hash())]);
+        assert_eq!(blockchain.validate_block(&other_chain),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::Script(ScriptError::False)}));
+        let mut other_script = scripted(&genesis_hash);
+        if let LedgerTransaction::Account(transaction) = &mut other_script {
+            transaction.script.as_mut().unwrap().script.0.pop();
+        }
+        let not_owner = mine_block(&blockchain,&block_2.hash(),vec![other_script]);
+        assert_eq!(blockchain.validate_block(&not_owner),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::NotOwner}));
+
+        let block_3 = mine_block(&blockchain,&block_2.hash(),vec![scripted(&genesis_hash)]);
+        blockchain.insert(&block_3);
+        assert_eq!(blockchain.tip(), block_3.hash());
+        assert_eq!(blockchain.tip_state().account(&locked), Some((1,amount(400))));
+    }
+
+    #[test]
+    fn batch_pays_every_output() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let batch = |nonce,values:&[(u8,u64)]| {
+            let outputs = values.iter().map(|(receiver,value)| (ico_address(*receiver),amount(*value))).collect();
+            let raw = Transaction{kind:TransactionKind::Batch{outputs},..raw_transaction(1,0,nonce,0,5)};
+            SignedTransaction::from_raw(raw,&genesis_hash,&ico_key_pair(1)).into()
+        };
+        let overspend = mine_block(&blockchain,&genesis_hash,vec![batch(1,&[(2,600),(3,400)])]);
+        assert_eq!(blockchain.validate_block(&overspend),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::InsufficientBalance{balance:amount(1000),cost:amount(1005)}}));
+        let overflow = mine_block(&blockchain,&genesis_hash,vec![batch(1,&[(2,MAX_SUPPLY.base_units()),(3,1)])]);
+        assert_eq!(blockchain.validate_block(&overflow),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::Overflow}));
+
+        let block = mine_block(&blockchain,&genesis_hash,vec![batch(1,&[(2,100),(3,200),(9,300),(2,50)])]);
+        blockchain.insert(&block);
+        assert_eq!(blockchain.tip(), block.hash());
+        let state = blockchain.tip_state();
+        assert_eq!(state.account(&ico_address(1)), Some((1,amount(345))));
+        assert_eq!(state.account(&ico_address(2)), Some((0,amount(2150))));
+        assert_eq!(state.account(&ico_address(3)), Some((0,amount(3200))));
+        assert_eq!(state.account(&ico_address(9)), Some((0,amount(300))));
+    }
+
+    #[test]
+    fn memo_index_follows_reorg() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let with_memo = |sender,memo:&[u8]| -> LedgerTransaction {
+            let raw = Transaction{memo:memo.to_vec(),..raw_transaction(sender,0,1,10,0)};
+            SignedTransaction::from_raw(raw,&genesis_hash,&ico_key_pair(sender)).into()
+        };
+        let too_long = mine_block(&blockchain,&genesis_hash,vec![with_memo(1,&[7;MAX_MEMO_SIZE+1])]);
+        assert_eq!(blockchain.validate_block(&too_long),
+            Err(BlockError::InvalidTransaction{index:0,error:TransactionError::MemoTooLarge{size:MAX_MEMO_SIZE+1}}));
+
+        let paid = with_memo(1,b"invoice-17");
+        let other = with_memo(2,b"invoice-18");
+        let block_1 = mine_block(&blockchain,&genesis_hash,vec![paid.clone(),other.clone(),with_memo(3,b"refund")]);
+        blockchain.insert(&block_1);
+        assert_eq!(blockchain.get_transaction(&paid.hash()), Some((&paid,block_1.hash())));
+        let found: Vec<H256> = blockchain.transactions_by_memo(b"invoice-",10).iter().map(|(tx,_)| tx.hash()).collect();
+        assert_eq!(found, vec![paid.hash(),other.hash()]);
+        assert_eq!(blockchain.transactions_by_memo(b"invoice-",1).len(), 1);
+        assert!(blockchain.transactions_by_memo(b"invoice-2",10).is_empty());
+
+        // a longer branch without `other` takes over and the index follows it
+        let branch = build_branch(&blockchain,&genesis_hash,vec![vec![paid.clone()],vec![]]);
+        for block in branch.iter() {
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.tip(), branch[1].hash());
+        assert_eq!(blockchain.get_transaction(&other.hash()), None);
+        assert_eq!(blockchain.get_transaction(&paid.hash()), Some((&paid,branch[0].hash())));
+        let found: Vec<H256> = blockchain.transactions_by_memo(b"invoice-",10).iter().map(|(tx,_)| tx.hash()).collect();
+        assert_eq!(found, vec![paid.hash()]);
+    }
+
+    #[test]
+    fn tokens_are_created_transferred_and_minted() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let create = transaction_of_kind(1,0,1,0,TransactionKind::TokenCreate{supply:1000});
+        let token = create.as_account().unwrap().trans_raw.hash();
+        let block_1 = mine_block(&blockchain,&genesis_hash,vec![create]);
+        blockchain.insert(&block_1);
+        let state = blockchain.tip_state();
+        assert_eq!(state.token(&token).map(|token| (token.issuer,token.supply)), Some((ico_address(1),1000)));
+        assert_eq!(state.token_balance(&token,&ico_address(1)), 1000);
+
+        let transfer = |sender,receiver,nonce,amount| transaction_of_kind(sender,receiver,nonce,0,TransactionKind::TokenTransfer{token,amount});
+        let mint = |sender,receiver,nonce,amount| transaction_of_kind(sender,receiver,nonce,0,TransactionKind::TokenMint{token,amount});
+        let rejected = [
+            (transfer(1,2,2,1001),TransactionError::InsufficientTokens{balance:1000,amount:1001}),
+            (transfer(2,3,1,1),TransactionError::InsufficientTokens{balance:0,amount:1}),
+            (transaction_of_kind(1,2,2,0,TransactionKind::TokenTransfer{token:H256::default(),amount:1}),TransactionError::UnknownToken),
+            (mint(2,2,1,5),TransactionError::NotIssuer),
+            (mint(1,2,2,u64::MAX),TransactionError::Overflow),
+        ];
+        for (transaction,error) in rejected.iter(){
+            let block = mine_block(&blockchain,&block_1.hash(),vec![transaction.clone()]);
+            assert_eq!(blockchain.validate_block(&block), Err(BlockError::InvalidTransaction{index:0,error:error.clone()}));
+        }
+
+        // the issuer sends 400, mints 50 more for account 3, and account 2 passes on 100 of what it got
+        let block_2 = mine_block(&blockchain,&block_1.hash(),vec![transfer(1,2,2,400),mint(1,3,3,50),transfer(2,3,1,100)]);
+        blockchain.insert(&block_2);
+        assert_eq!(blockchain.tip(), block_2.hash());
+        let state = blockchain.tip_state();
+        assert_eq!(state.token(&token).map(|token| token.supply), Some(1050));
+        let balances: Vec<u64> = (1..=3).map(|i| state.token_balance(&token,&ico_address(i))).collect();
+        assert_eq!(balances, vec![600,300,150]);
+        // token transactions only pay the native fee
+        assert_eq!(state.account(&ico_address(1)), Some((3,amount(1000))));
+
+        // a longer branch without them takes everything back
+        let branch = build_branch(&blockchain,&genesis_hash,vec![vec![],vec![],vec![]]);
+        for block in branch.iter() {
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.tip(), branch[2].hash());
+        assert!(blockchain.tip_state().token(&token).is_none());
+        assert_eq!(blockchain.tip_state().token_balance(&token,&ico_address(1)), 0);
+    }
+}
@@ -0,0 +1,69 @@
+//! Consensus parameters of a blockchain.
+
+use crate::basic::amount::Amount;
+use crate::basic::block::default_difficulty;
+use crate::basic::ledger::LedgerModel;
+use crate::crypto::hash::{H256, Hashable};
+use serde::{Deserialize, Serialize};
+
+/// Parameters that every node of one network must agree on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChainConfig {
+    /// The easiest target a block may use; retargeting never goes above it.
+    pub pow_limit: H256,
+    /// The block interval the difficulty adjustment aims for, in milliseconds.
+    pub block_interval_ms: u64,
+    /// The difficulty is recomputed every `retarget_window` blocks from the time the last
+    /// window took.
+    pub retarget_window: u64,
+    /// The largest serialized block accepted, in bytes.
+    pub max_block_size: usize,
+    /// How far ahead of the local clock a block timestamp may be, in milliseconds.
+    pub max_future_drift_ms: u64,
+    /// How many blocks a reorganization may roll back. Undo records of older blocks are dropped.
+    pub max_reorg_depth: u64,
+    /// The new coins the coinbase of the first block may claim on top of the fees.
+    pub initial_subsidy: Amount,
+    /// The subsidy halves every `halving_interval` blocks, or never if it is 0.
+    pub halving_interval: u64,
+    /// How many blocks after its own a coinbase is credited and can be spent.
+    pub coinbase_maturity: u64,
+    /// The ledger model, which also decides the genesis block.
+    pub ledger: LedgerModel,
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        ChainConfig {
+            pow_limit: default_difficulty().into(),
+            block_interval_ms: 2000,
+            retarget_window: 20,
+            max_block_size: 1_000_000,
+            max_future_drift_ms: 2 * 60 * 60 * 1000,
+            max_reorg_depth: 100,
+            initial_subsidy: Amount::from_base_units(50),
+            halving_interval: 10_000,
+            coinbase_maturity: 10,
+            ledger: LedgerModel::Account,
+        }
+    }
+}
+
+impl Hashable for ChainConfig {
+    /// The network the config defines, which its genesis block commits to
+    fn hash(&self) -> H256 {
+        let bytes = bincode::serialize(&self).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &bytes).into()
+    }
+}
+
+impl ChainConfig {
+    /// The new coins a block at `height` may create.
+    pub fn block_subsidy(&self, height: u64) -> Amount {
+        let halvings = height.checked_div(self.halving_interval).unwrap_or(0);
+        if halvings >= u64::BITS as u64 {
+            return Amount::ZERO;
+        }
+        Amount::from_base_units(self.initial_subsidy.base_units() >> halvings)
+    }
+}
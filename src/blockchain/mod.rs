@@ -1 +1,6 @@
-pub mod blockchain;
\ No newline at end of file
+pub mod blockchain;
+pub mod config;
+pub mod orphan;
+pub mod store;
+pub mod tx_index;
+pub mod validation;
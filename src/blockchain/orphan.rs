@@ -0,0 +1,203 @@
+//! Blocks that arrived before their parent.
+
+use crate::basic::block::Block;
+use crate::crypto::hash::{H256, Hashable};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Default cap on the number of buffered orphans
+pub const DEFAULT_MAX_ORPHANS: usize = 500;
+/// Default cap on the serialized size of all buffered orphans, in bytes
+pub const DEFAULT_MAX_ORPHAN_BYTES: usize = 20_000_000;
+/// Default time an orphan waits for its parent before it is dropped, in milliseconds
+pub const DEFAULT_ORPHAN_EXPIRY_MS: u128 = 20 * 60 * 1000;
+
+struct OrphanEntry {
+    block: Block,
+    size: usize,
+    arrival: u128,
+    seq: u64,
+}
+
+/// Orphan blocks, indexed by the parent they are waiting for.
+///
+/// The pool is capped by count and by bytes; when it is full the oldest orphans go first.
+/// Orphans older than the expiry are dropped whenever a new one comes in.
+pub struct OrphanPool {
+    orphans: HashMap<H256, OrphanEntry>,
+    by_parent: HashMap<H256, Vec<H256>>,
+    by_arrival: BTreeMap<u64, H256>,
+    next_seq: u64,
+    total_bytes: usize,
+    max_count: usize,
+    max_bytes: usize,
+    expiry_ms: u128,
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        OrphanPool::new(DEFAULT_MAX_ORPHANS, DEFAULT_MAX_ORPHAN_BYTES, DEFAULT_ORPHAN_EXPIRY_MS)
+    }
+}
+
+impl OrphanPool {
+    pub fn new(max_count: usize, max_bytes: usize, expiry_ms: u128) -> Self {
+        OrphanPool {
+            orphans: HashMap::new(),
+            by_parent: HashMap::new(),
+            by_arrival: BTreeMap::new(),
+            next_seq: 0,
+            total_bytes: 0,
+            max_count,
+            max_bytes,
+            expiry_ms,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    /// Buffer an orphan that arrived at `now` (milliseconds since the epoch).
+    /// Returns false if it was already buffered or is too large to ever fit.
+    pub fn insert(&mut self, block: Block, now: u128) -> bool {
+        let hash = block.hash();
+        let size = block.size();
+        if self.orphans.contains_key(&hash) || size > self.max_bytes || self.max_count == 0 {
+            return false;
+        }
+        self.expire(now);
+        while self.orphans.len() >= self.max_count || self.total_bytes + size > self.max_bytes {
+            let oldest = *self.by_arrival.values().next().unwrap();
+            self.remove(&oldest);
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.by_parent.entry(block.header.parent).or_default().push(hash);
+        self.by_arrival.insert(seq, hash);
+        self.total_bytes += size;
+        self.orphans.insert(hash, OrphanEntry { block, size, arrival: now, seq });
+        true
+    }
+
+    /// Remove and return the orphans waiting for `parent`.
+    pub fn take_children(&mut self, parent: &H256) -> Vec<Block> {
+        let children = self.by_parent.get(parent).cloned().unwrap_or_default();
+        children.iter().filter_map(|hash| self.remove(hash)).collect()
+    }
+
+    /// Drop the orphans that have waited longer than the expiry.
+    pub fn expire(&mut self, now: u128) {
+        let expired: Vec<H256> = self
+            .by_arrival
+            .values()
+            .take_while(|hash| self.orphans[*hash].arrival + self.expiry_ms <= now)
+            .copied()
+            .collect();
+        for hash in expired.iter() {
+            self.remove(hash);
+        }
+    }
+
+    /// The blocks that orphans are waiting for and that are not orphans themselves,
+    /// i.e. what has to be fetched to connect every buffered orphan.
+    pub fn missing_parents(&self) -> Vec<H256> {
+        let mut seen = HashSet::new();
+        self.by_arrival
+            .values()
+            .map(|hash| self.orphans[hash].block.header.parent)
+            .filter(|parent| !self.orphans.contains_key(parent) && seen.insert(*parent))
+            .collect()
+    }
+
+    fn remove(&mut self, hash: &H256) -> Option<Block> {
+        let entry = self.orphans.remove(hash)?;
+        let parent = entry.block.header.parent;
+        if let Some(siblings) = self.by_parent.get_mut(&parent) {
+            siblings.retain(|sibling| sibling != hash);
+            if siblings.is_empty() {
+                self.by_parent.remove(&parent);
+            }
+        }
+        self.by_arrival.remove(&entry.seq);
+        self.total_bytes -= entry.size;
+        Some(entry.block)
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::basic::block::test::generate_random_block;
+    use crate::crypto::hash::tests::generate_random_hash;
+
+    #[test]
+    fn children_are_found_by_parent() {
+        let mut pool = OrphanPool::default();
+        let parent = generate_random_hash();
+        let child_1 = generate_random_block(&parent);
+        let child_2 = generate_random_block(&parent);
+        let grandchild = generate_random_block(&child_1.hash());
+        assert!(pool.insert(child_1.clone(), 0));
+        assert!(pool.insert(child_2.clone(), 0));
+        assert!(pool.insert(grandchild.clone(), 0));
+        assert!(!pool.insert(grandchild.clone(), 0));
+        assert_eq!(pool.missing_parents(), vec![parent]);
+
+        let children: Vec<H256> = pool.take_children(&parent).iter().map(|block| block.hash()).collect();
+        assert_eq!(children, vec![child_1.hash(), child_2.hash()]);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.missing_parents(), vec![child_1.hash()]);
+        assert_eq!(pool.take_children(&child_1.hash()).len(), 1);
+        assert!(pool.is_empty());
+        assert_eq!(pool.total_bytes(), 0);
+    }
+
+    #[test]
+    fn oldest_are_evicted_when_full() {
+        let size = generate_random_block(&generate_random_hash()).size();
+        let mut pool = OrphanPool::new(3, 10 * size, 1000);
+        let blocks: Vec<Block> = (0..4).map(|_| generate_random_block(&generate_random_hash())).collect();
+        for (i, block) in blocks.iter().enumerate() {
+            assert!(pool.insert(block.clone(), i as u128));
+        }
+        assert_eq!(pool.len(), 3);
+        assert!(!pool.contains(&blocks[0].hash()));
+        assert!(pool.contains(&blocks[3].hash()));
+
+        // the byte cap evicts as well
+        let mut pool = OrphanPool::new(10, 2 * size, 1000);
+        for (i, block) in blocks.iter().enumerate() {
+            pool.insert(block.clone(), i as u128);
+        }
+        assert_eq!(pool.len(), 2);
+        assert!(pool.contains(&blocks[2].hash()) && pool.contains(&blocks[3].hash()));
+        assert_eq!(pool.total_bytes(), 2 * size);
+    }
+
+    #[test]
+    fn orphans_expire() {
+        let mut pool = OrphanPool::new(10, 1_000_000, 1000);
+        let old = generate_random_block(&generate_random_hash());
+        let new = generate_random_block(&generate_random_hash());
+        pool.insert(old.clone(), 0);
+        pool.insert(new.clone(), 500);
+        pool.expire(999);
+        assert_eq!(pool.len(), 2);
+        pool.expire(1000);
+        assert!(!pool.contains(&old.hash()));
+        assert!(pool.contains(&new.hash()));
+        assert_eq!(pool.missing_parents(), vec![new.header.parent]);
+    }
+}
@@ -0,0 +1,200 @@
+//! Append-only on-disk storage for blocks.
+//!
+//! Blocks are appended to `blocks.dat` as bincode records, and every record gets a fixed-size
+//! entry in `index.dat` (hash, kind, offset, length). Both files start with a magic and the
+//! format version. Reopening the store replays the index in order, so the blockchain can rebuild
+//! its tip, heights and states exactly as they were.
+//! `config.dat` holds the chain config the blocks were validated under, ledger model included.
+
+use super::config::ChainConfig;
+use crate::basic::block::Block;
+use crate::crypto::hash::{H256, Hashable};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const BLOCKS_FILE: &str = "blocks.dat";
+const INDEX_FILE: &str = "index.dat";
+const CONFIG_FILE: &str = "config.dat";
+/// What `blocks.dat` starts with, followed by the format version
+const BLOCKS_MAGIC: &[u8; 4] = b"BLKS";
+/// What `index.dat` starts with, followed by the format version
+const INDEX_MAGIC: &[u8; 4] = b"BIDX";
+/// Bumped whenever the stored format changes, so old blocks are not misread
+pub const STORE_VERSION: u32 = 1;
+/// magic (4) + version (4)
+const HEADER_SIZE: u64 = 8;
+/// hash (32) + kind (1) + offset (8) + length (4)
+const INDEX_ENTRY_SIZE: usize = 45;
+
+/// What a stored block was when it was written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    /// The block was connected to the chain.
+    Block,
+    /// The block arrived before its parent and went to the orphan buffer.
+    Orphan,
+}
+
+impl RecordKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            RecordKind::Block => 0,
+            RecordKind::Orphan => 1,
+        }
+    }
+    fn from_byte(byte: u8) -> Option<RecordKind> {
+        match byte {
+            0 => Some(RecordKind::Block),
+            1 => Some(RecordKind::Orphan),
+            _ => None,
+        }
+    }
+}
+
+pub struct BlockStore {
+    blocks: File,
+    index: File,
+    blocks_len: u64,
+    index_len: u64,
+}
+
+impl BlockStore {
+    /// Open (or create) the store in `dir` for a chain with `config` and return every intact
+    /// record in write order. A store created with another config is refused, since its blocks
+    /// were validated under other rules.
+    ///
+    /// A record whose index entry or block bytes are incomplete (e.g. the process died in the
+    /// middle of an append) ends the log: it and everything after it are truncated away. A
+    /// complete record that does not decode, or a file of another format version, is an
+    /// `InvalidData` error and leaves the files as they are.
+    pub fn open(dir: &Path, config: &ChainConfig) -> io::Result<(BlockStore, Vec<(RecordKind, Block)>)> {
+        fs::create_dir_all(dir)?;
+        check_config(dir, config)?;
+        let mut blocks = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(dir.join(BLOCKS_FILE))?;
+        let mut index = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(dir.join(INDEX_FILE))?;
+        check_header(&mut blocks, BLOCKS_MAGIC)?;
+        check_header(&mut index, INDEX_MAGIC)?;
+        let mut index_bytes = Vec::new();
+        index.read_to_end(&mut index_bytes)?;
+        let blocks_size = blocks.metadata()?.len();
+
+        let mut records = Vec::new();
+        let mut blocks_len = HEADER_SIZE;
+        for entry in index_bytes.chunks(INDEX_ENTRY_SIZE) {
+            if entry.len() < INDEX_ENTRY_SIZE {
+                break;
+            }
+            let mut raw_hash = [0u8; 32];
+            raw_hash.copy_from_slice(&entry[0..32]);
+            let hash = H256::from(raw_hash);
+            let offset = u64::from_le_bytes(entry[33..41].try_into().unwrap());
+            let len = u32::from_le_bytes(entry[41..45].try_into().unwrap()) as u64;
+            if offset.saturating_add(len) > blocks_size {
+                break;
+            }
+            let kind = RecordKind::from_byte(entry[32]).ok_or_else(|| invalid_record(records.len(), "unknown record kind"))?;
+            if offset != blocks_len {
+                return Err(invalid_record(records.len(), "offset out of sequence"));
+            }
+            let mut buf = vec![0u8; len as usize];
+            blocks.seek(SeekFrom::Start(offset))?;
+            blocks.read_exact(&mut buf)?;
+            let block: Block = bincode::deserialize(&buf).map_err(|e| invalid_record(records.len(), e))?;
+            if block.hash() != hash {
+                return Err(invalid_record(records.len(), "hash mismatch"));
+            }
+            records.push((kind, block));
+            blocks_len += len;
+        }
+        let index_len = HEADER_SIZE + (records.len() * INDEX_ENTRY_SIZE) as u64;
+        index.set_len(index_len)?;
+        index.seek(SeekFrom::End(0))?;
+        blocks.set_len(blocks_len)?;
+        blocks.seek(SeekFrom::End(0))?;
+        Ok((BlockStore { blocks, index, blocks_len, index_len }, records))
+    }
+
+    /// Append a block to the log.
+    pub fn append(&mut self, kind: RecordKind, block: &Block) -> io::Result<()> {
+        let bytes = bincode::serialize(block).map_err(io::Error::other)?;
+        let mut entry = Vec::with_capacity(INDEX_ENTRY_SIZE);
+        entry.extend_from_slice(block.hash().as_ref());
+        entry.push(kind.to_byte());
+        entry.extend_from_slice(&self.blocks_len.to_le_bytes());
+        entry.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        if let Err(e) = self.write_record(&bytes, &entry) {
+            // drop whatever part of the record made it, so the next append lands where the
+            // index expects it
+            self.blocks.set_len(self.blocks_len)?;
+            self.blocks.seek(SeekFrom::End(0))?;
+            self.index.set_len(self.index_len)?;
+            self.index.seek(SeekFrom::End(0))?;
+            return Err(e);
+        }
+        self.blocks_len += bytes.len() as u64;
+        self.index_len += entry.len() as u64;
+        Ok(())
+    }
+
+    fn write_record(&mut self, bytes: &[u8], entry: &[u8]) -> io::Result<()> {
+        // the block goes first, so a crash never leaves an index entry pointing at missing bytes
+        self.blocks.write_all(bytes)?;
+        self.blocks.sync_data()?;
+        self.index.write_all(entry)?;
+        self.index.sync_data()
+    }
+}
+
+/// Check the magic and version `file` starts with, writing them if the file is new (or died
+/// while being created), and leave the cursor after them
+fn check_header(file: &mut File, magic: &[u8; 4]) -> io::Result<()> {
+    let mut header = magic.to_vec();
+    header.extend_from_slice(&STORE_VERSION.to_le_bytes());
+    let mut saved = Vec::new();
+    Read::by_ref(file).take(HEADER_SIZE).read_to_end(&mut saved)?;
+    if (saved.len() as u64) < HEADER_SIZE && header.starts_with(&saved) {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        return file.sync_data();
+    }
+    if saved.len() < HEADER_SIZE as usize || &saved[..4] != magic {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a block store file"));
+    }
+    let version = u32::from_le_bytes(saved[4..8].try_into().unwrap());
+    if version != STORE_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("block store version {}, expected {}", version, STORE_VERSION),
+        ));
+    }
+    file.seek(SeekFrom::Start(HEADER_SIZE)).map(|_| ())
+}
+
+fn invalid_record<E: std::fmt::Display>(position: usize, error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("stored record {} is unreadable: {}", position, error))
+}
+
+/// Compare `config` with the one saved in `dir`, saving it if there is none yet
+fn check_config(dir: &Path, config: &ChainConfig) -> io::Result<()> {
+    let path = dir.join(CONFIG_FILE);
+    match fs::read(&path) {
+        Ok(bytes) => {
+            let saved: ChainConfig = bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if saved != *config {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("the store was created with another chain config: {:?}", saved),
+                ));
+            }
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let bytes = bincode::serialize(config).map_err(io::Error::other)?;
+            fs::write(&path, bytes)
+        }
+        Err(e) => Err(e),
+    }
+}
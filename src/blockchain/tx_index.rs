@@ -0,0 +1,65 @@
+//! Where the transactions of the main chain are.
+
+use crate::basic::block::Block;
+use crate::crypto::hash::{H256, Hashable};
+use std::collections::{BTreeSet, HashMap};
+
+/// The block and position of every transaction on the main chain, and the transactions by memo.
+///
+/// The blockchain adds a block when it connects it and removes it when it disconnects it, so the
+/// index follows reorganizations.
+#[derive(Debug, Default)]
+pub struct TxIndex {
+    locations: HashMap<H256, (H256, usize)>,
+    memos: BTreeSet<(Vec<u8>, H256)>,
+}
+
+impl TxIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+
+    /// Index the transactions of a block that joined the main chain
+    pub fn add_block(&mut self, block: &Block) {
+        let hash = block.hash();
+        for (position, transaction) in block.content.transactions.iter().enumerate() {
+            let txid = transaction.hash();
+            self.locations.insert(txid, (hash, position));
+            if !transaction.memo().is_empty() {
+                self.memos.insert((transaction.memo().to_vec(), txid));
+            }
+        }
+    }
+
+    /// Forget the transactions of a block that left the main chain
+    pub fn remove_block(&mut self, block: &Block) {
+        for transaction in block.content.transactions.iter() {
+            let txid = transaction.hash();
+            self.locations.remove(&txid);
+            self.memos.remove(&(transaction.memo().to_vec(), txid));
+        }
+    }
+
+    /// The hash of the block that has the transaction, and its position in the block
+    pub fn location(&self, txid: &H256) -> Option<(H256, usize)> {
+        self.locations.get(txid).copied()
+    }
+
+    /// Up to `limit` transactions whose memo starts with `prefix`, ordered by memo
+    pub fn search_memo(&self, prefix: &[u8], limit: usize) -> Vec<H256> {
+        self.memos
+            .range((prefix.to_vec(), H256::default())..)
+            .take_while(|(memo, _)| memo.starts_with(prefix))
+            .take(limit)
+            .map(|(_, txid)| *txid)
+            .collect()
+    }
+}
@@ -0,0 +1,115 @@
+//! Reasons a block is rejected, and the checks that need nothing but the block itself.
+
+use crate::basic::block::Block;
+use crate::basic::amount::Amount;
+use crate::crypto::hash::{H256, Hashable};
+use crate::transaction::transaction::TransactionError;
+use super::config::ChainConfig;
+use std::collections::HashSet;
+
+/// Why a block was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    /// The hash does not meet the header's target, or the target is above the limit.
+    BadProofOfWork,
+    /// The target is not the one the retargeting rule gives for this height.
+    BadDifficulty,
+    UnknownParent,
+    /// The parent or an earlier ancestor is invalid.
+    InvalidAncestor,
+    BadMerkleRoot,
+    /// The body repeats a transaction. Repeating the last one keeps the merkle root, so this is
+    /// a copy of the mined body at best.
+    DuplicateTransaction,
+    /// The timestamp is not after the median of the recent ancestors.
+    TimestampTooEarly,
+    /// The timestamp is too far ahead of the local clock.
+    TimestampInFuture,
+    TooLarge { size: usize },
+    /// The transaction at `index` is invalid.
+    InvalidTransaction { index: usize, error: TransactionError },
+    /// The coinbase is not for the height of its block.
+    BadCoinbaseHeight,
+    /// The coinbase claims more than the subsidy and the fees.
+    ExcessiveCoinbase { claimed: Amount, allowed: Amount },
+    /// Crediting a matured reward would take a balance above the total supply.
+    RewardOverflow,
+}
+
+impl BlockError {
+    /// Whether the block can be remembered as invalid. A bad merkle root or a repeated
+    /// transaction only says the body we got is not the one that was mined, and anyone relaying
+    /// the block can pad or mutate it, so neither condemns the header hash; nor does the size,
+    /// which is that of the body. A future timestamp may become fine later.
+    pub fn marks_invalid(&self) -> bool {
+        !matches!(
+            self,
+            BlockError::UnknownParent
+                | BlockError::BadMerkleRoot
+                | BlockError::DuplicateTransaction
+                | BlockError::TooLarge { .. }
+                | BlockError::TimestampInFuture
+        )
+    }
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockError::BadProofOfWork => write!(f, "bad proof of work"),
+            BlockError::BadDifficulty => write!(f, "unexpected difficulty"),
+            BlockError::UnknownParent => write!(f, "unknown parent"),
+            BlockError::InvalidAncestor => write!(f, "descends from an invalid block"),
+            BlockError::BadMerkleRoot => write!(f, "merkle root does not match the transactions"),
+            BlockError::DuplicateTransaction => write!(f, "a transaction is repeated"),
+            BlockError::TimestampTooEarly => write!(f, "timestamp is not after the median time past"),
+            BlockError::TimestampInFuture => write!(f, "timestamp is too far in the future"),
+            BlockError::TooLarge { size } => write!(f, "block of {} bytes is too large", size),
+            BlockError::InvalidTransaction { index, error } => write!(f, "transaction {}: {}", index, error),
+            BlockError::BadCoinbaseHeight => write!(f, "coinbase height does not match the block"),
+            BlockError::RewardOverflow => write!(f, "a matured reward takes a balance above the total supply"),
+            BlockError::ExcessiveCoinbase { claimed, allowed } => {
+                write!(f, "coinbase claims {} but only {} is allowed", claimed, allowed)
+            }
+        }
+    }
+}
+
+/// Check everything that does not depend on the chain's state: merkle root, size, memo sizes and
+/// the signatures, which must be made for the chain `chain_id`. The body is matched with the
+/// header first, so that nothing after condemns a block for what a relay did to its body.
+pub fn check_block(block: &Block, config: &ChainConfig, chain_id: &H256) -> Result<(), BlockError> {
+    if block.content.merkle_root() != block.header.merkle_root {
+        return Err(BlockError::BadMerkleRoot);
+    }
+    let mut seen = HashSet::new();
+    if !block.content.transactions.iter().all(|transaction| seen.insert(transaction.hash())) {
+        return Err(BlockError::DuplicateTransaction);
+    }
+    let size = block.size();
+    if size > config.max_block_size {
+        return Err(BlockError::TooLarge { size });
+    }
+    for (index, transaction) in block.content.transactions.iter().enumerate() {
+        if !transaction.verify_signatures(chain_id) {
+            return Err(BlockError::InvalidTransaction { index, error: TransactionError::BadSignature });
+        }
+        transaction.check_memo().map_err(|error| BlockError::InvalidTransaction { index, error })?;
+    }
+    Ok(())
+}
+
+/// Check the coinbase of a block at `height`: it has to be for that height and may claim at most
+/// the subsidy plus the fees of the block.
+pub fn check_coinbase(block: &Block, height: u64, config: &ChainConfig) -> Result<(), BlockError> {
+    if let Some(coinbase) = &block.content.coinbase {
+        if coinbase.height != height {
+            return Err(BlockError::BadCoinbaseHeight);
+        }
+        let allowed = config.block_subsidy(height).saturating_add(block.content.total_fees());
+        if coinbase.value > allowed {
+            return Err(BlockError::ExcessiveCoinbase { claimed: coinbase.value, allowed });
+        }
+    }
+    Ok(())
+}
@@ -107,6 +107,122 @@ impl PartialOrd for H256 {
     }
 }
 
+/// 256-bit unsigned arithmetic, treating the hash as a big-endian integer.
+impl H256 {
+    /// The largest value, 2^256 - 1.
+    pub fn max_value() -> H256 {
+        H256([0xff; 32])
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|byte| *byte == 0)
+    }
+
+    fn to_limbs(self) -> [u64; 4] {
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = u64::from_be_bytes(self.0[i * 8..i * 8 + 8].try_into().unwrap());
+        }
+        limbs
+    }
+
+    fn from_limbs(limbs: [u64; 4]) -> H256 {
+        let mut raw = [0u8; 32];
+        for (i, limb) in limbs.iter().enumerate() {
+            raw[i * 8..i * 8 + 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        H256(raw)
+    }
+
+    /// `self + other`, or `None` on overflow.
+    pub fn checked_add(&self, other: &H256) -> Option<H256> {
+        let (a, b) = (self.to_limbs(), other.to_limbs());
+        let mut out = [0u64; 4];
+        let mut carry = false;
+        for i in (0..4).rev() {
+            let (sum, c1) = a[i].overflowing_add(b[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            out[i] = sum;
+            carry = c1 || c2;
+        }
+        if carry { None } else { Some(H256::from_limbs(out)) }
+    }
+
+    /// `self - other`, or `None` if `other` is larger.
+    pub fn checked_sub(&self, other: &H256) -> Option<H256> {
+        let (a, b) = (self.to_limbs(), other.to_limbs());
+        let mut out = [0u64; 4];
+        let mut borrow = false;
+        for i in (0..4).rev() {
+            let (diff, b1) = a[i].overflowing_sub(b[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            out[i] = diff;
+            borrow = b1 || b2;
+        }
+        if borrow { None } else { Some(H256::from_limbs(out)) }
+    }
+
+    /// `self * factor`, or `None` on overflow.
+    pub fn checked_mul_u64(&self, factor: u64) -> Option<H256> {
+        let a = self.to_limbs();
+        let mut out = [0u64; 4];
+        let mut carry = 0u128;
+        for i in (0..4).rev() {
+            let product = a[i] as u128 * factor as u128 + carry;
+            out[i] = product as u64;
+            carry = product >> 64;
+        }
+        if carry != 0 { None } else { Some(H256::from_limbs(out)) }
+    }
+
+    /// Integer division, or `None` if `divisor` is zero.
+    pub fn checked_div(&self, divisor: &H256) -> Option<H256> {
+        if divisor.is_zero() {
+            return None;
+        }
+        // binary long division, one bit of the dividend at a time
+        let mut quotient = H256::default();
+        let mut remainder = H256::default();
+        for bit in 0..256 {
+            remainder = remainder.shl1();
+            if self.0[bit / 8] & (0x80 >> (bit % 8)) != 0 {
+                remainder.0[31] |= 1;
+            }
+            if remainder >= *divisor {
+                remainder = remainder.checked_sub(divisor).unwrap();
+                quotient.0[bit / 8] |= 0x80 >> (bit % 8);
+            }
+        }
+        Some(quotient)
+    }
+
+    fn shl1(&self) -> H256 {
+        let mut raw = [0u8; 32];
+        for (i, byte) in raw.iter_mut().enumerate() {
+            *byte = self.0[i] << 1;
+            if i < 31 {
+                *byte |= self.0[i + 1] >> 7;
+            }
+        }
+        H256(raw)
+    }
+}
+
+impl std::ops::Not for H256 {
+    type Output = H256;
+    fn not(self) -> H256 {
+        let mut raw = self.0;
+        raw.iter_mut().for_each(|byte| *byte = !*byte);
+        H256(raw)
+    }
+}
+
+impl std::convert::From<u64> for H256 {
+    fn from(input: u64) -> H256 {
+        H256::from_limbs([0, 0, 0, input])
+    }
+}
+
 #[cfg(any(test, test_utilities))]
 pub mod tests {
     use super::H256;
@@ -120,4 +236,27 @@ pub mod tests {
         (&raw_bytes).into()
     }
 
+    #[test]
+    fn add_sub_carry() {
+        let a: H256 = (hex!("00000000000000000000000000000000ffffffffffffffffffffffffffffffff")).into();
+        let one = H256::from(1u64);
+        let sum = a.checked_add(&one).unwrap();
+        assert_eq!(sum, (hex!("0000000000000000000000000000000100000000000000000000000000000000")).into());
+        assert_eq!(sum.checked_sub(&one).unwrap(), a);
+        assert_eq!(one.checked_sub(&sum), None);
+        assert_eq!(H256::max_value().checked_add(&one), None);
+    }
+
+    #[test]
+    fn mul_div() {
+        let a = generate_random_hash();
+        let a: H256 = (!a).checked_div(&H256::from(1000u64)).unwrap();
+        let product = a.checked_mul_u64(1000).unwrap();
+        assert_eq!(product.checked_div(&H256::from(1000u64)).unwrap(), a);
+        assert_eq!(product.checked_div(&a).unwrap(), H256::from(1000u64));
+        assert_eq!(a.checked_div(&H256::default()), None);
+        assert_eq!(H256::max_value().checked_mul_u64(2), None);
+        let big: H256 = (hex!("0100000000000000000000000000000000000000000000000000000000000000")).into();
+        assert_eq!(H256::max_value().checked_div(&big).unwrap(), H256::from(0xffu64));
+    }
 }
@@ -1,5 +1,6 @@
 use ring::rand;
 use ring::signature::Ed25519KeyPair;
+use super::hash::H256;
 
 /// Generate a random key pair.
 pub fn random() -> Ed25519KeyPair {
@@ -7,3 +8,14 @@ pub fn random() -> Ed25519KeyPair {
     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
     Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
 }
+
+/// The bytes actually signed for `payload`: the length and name of the domain, i.e. what kind of
+/// object is signed, then the chain id. A signature is only valid for one kind of object on one chain.
+pub fn signing_message(domain: &[u8], chain_id: &H256, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(1 + domain.len() + 32 + payload.len());
+    message.push(domain.len() as u8);
+    message.extend_from_slice(domain);
+    message.extend_from_slice(chain_id.as_ref());
+    message.extend_from_slice(payload);
+    message
+}
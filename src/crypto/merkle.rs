@@ -135,6 +135,7 @@ pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size
 mod tests {
     use crate::crypto::hash::H256;
     use super::*;
+    use log::info;
 
     macro_rules! gen_merkle_tree_data {
         () => {{
@@ -17,12 +17,17 @@ use basic::mempool::Mempool;
 use network::{server, worker};
 use transaction::transaction_generator;
 use std::net;
+use std::path;
 use std::process;
 use std::thread;
 use std::time;
 use std::sync::{Arc, Mutex};
 use blockchain::blockchain::Blockchain;
+use blockchain::config::ChainConfig;
+use basic::ledger::LedgerModel;
 use api::miner;
+use api::address::H160 as Address;
+use ring::signature::KeyPair;
 
 fn main() {
     // parse command line arguments
@@ -34,6 +39,12 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory where the blockchain is stored across restarts")
+     (@arg block_interval: --("block-interval") [MS] default_value("2000") "Sets the block interval in milliseconds that difficulty retargeting aims for")
+     (@arg retarget_window: --("retarget-window") [INT] default_value("20") "Sets the number of blocks between difficulty retargets")
+     (@arg rbf_bump: --("rbf-bump") [PERCENT] default_value("10") "Sets how much a transaction has to raise the fee to replace one with the same nonce")
+     (@arg ledger: --ledger [MODEL] default_value("account") "Sets the ledger model of a new chain, account or utxo")
+     (@arg mempool_lifetime: --("mempool-lifetime") [MINUTES] default_value("180") "Sets how long a transaction may wait in the mempool")
     )
     .get_matches();
 
@@ -68,16 +79,92 @@ fn main() {
     let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
     server_ctx.start().unwrap();
 
+    // parse consensus parameters
+    let block_interval_ms = matches
+        .value_of("block_interval")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block interval: {}", e);
+            process::exit(1);
+        });
+    let retarget_window = matches
+        .value_of("retarget_window")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing retarget window: {}", e);
+            process::exit(1);
+        });
+    let ledger = matches
+        .value_of("ledger")
+        .unwrap()
+        .parse::<LedgerModel>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ledger model: {}", e);
+            process::exit(1);
+        });
+    let config = ChainConfig {
+        block_interval_ms,
+        retarget_window,
+        ledger,
+        ..ChainConfig::default()
+    };
+
     // create the Blockchain
-    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
-    let mempool=Arc::new(Mutex::new(Mempool::new()));
+    let blockchain = match matches.value_of("data_dir") {
+        Some(dir) => Blockchain::open(path::Path::new(dir), config).unwrap_or_else(|e| {
+            error!("Error opening blockchain in {}: {}", dir, e);
+            process::exit(1);
+        }),
+        None => Blockchain::with_config(config),
+    };
+    let rbf_bump = matches
+        .value_of("rbf_bump")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing replacement fee bump: {}", e);
+            process::exit(1);
+        });
+    let mempool_lifetime = matches
+        .value_of("mempool_lifetime")
+        .unwrap()
+        .parse::<u128>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool lifetime: {}", e);
+            process::exit(1);
+        });
+    // reload the mempool saved at the last shutdown
+    let mut mempool = Mempool::new()
+        .with_replacement_bump(rbf_bump)
+        .with_lifetime(mempool_lifetime * 60 * 1000)
+        .with_chain_id(blockchain.chain_id());
+    let mempool_path = matches.value_of("data_dir").map(|dir| path::Path::new(dir).join("mempool.dat"));
+    if let Some(path) = &mempool_path {
+        match mempool.load(path, blockchain.tip_state()) {
+            Ok(count) => info!("Loaded {} transactions into the mempool", count),
+            Err(e) => error!("Error loading the mempool from {}: {}", path.display(), e),
+        }
+    }
+    let blockchain = Arc::new(Mutex::new(blockchain));
+    let mempool=Arc::new(Mutex::new(mempool));
     //start the transaction_generator
-    let key_pair=key_pair::random();
+    // the rewards go to a key kept in the data directory, so they can still be spent after a restart
+    let miner_key = match matches.value_of("data_dir") {
+        Some(dir) => key_pair::load_or_create(&path::Path::new(dir).join("miner.key")).unwrap_or_else(|e| {
+            error!("Error loading the miner key from {}: {}", dir, e);
+            process::exit(1);
+        }),
+        None => {
+            info!("No data directory, block rewards go to a key that is lost on exit");
+            key_pair::random()
+        }
+    };
     let transaction_generator=transaction_generator::TransactionGenerator::new(
         &server,
         &mempool,
         &blockchain,
-        key_pair
     );
     //debug!("start transaction generator!\n");
     transaction_generator.start();
@@ -99,11 +186,14 @@ fn main() {
     );
     worker_ctx.start();
 
-    // start the miner
+    // start the miner, paying the rewards to the node's own key
+    let miner_address = Address::from_pubkey(miner_key.public_key().as_ref());
+    info!("block rewards go to {}", miner_address);
     let (miner_ctx, miner) = miner::new(
         &server,
         &blockchain,
         &mempool,
+        miner_address,
     );
     miner_ctx.start();
 
@@ -146,6 +236,9 @@ fn main() {
         api_addr,
         &miner,
         &server,
+        &mempool,
+        &blockchain,
+        mempool_path,
     );
 
     loop {
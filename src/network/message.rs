@@ -1,7 +1,7 @@
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::H256;
 use crate::basic::block::Block;
-use crate::transaction::transaction::SignedTransaction;
+use crate::transaction::transaction::LedgerTransaction;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     Ping(String),
@@ -11,5 +11,5 @@ pub enum Message {
     Blocks(Vec<Block>),
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
-    Transactions(Vec<SignedTransaction>),
+    Transactions(Vec<LedgerTransaction>),
 }
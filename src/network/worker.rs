@@ -4,10 +4,11 @@ use crate::basic::block::Block;
 use crate::crypto::hash::{H256, Hashable};
 use crate::basic::mempool::{Mempool, self};
 use crate::network::server::Handle as ServerHandle;
-use crate::transaction::transaction::{SignedTransaction,Transaction};
+use crate::transaction::transaction::LedgerTransaction;
 use crossbeam::channel;
 use log::{debug, warn, info};
-use crate::blockchain::blockchain::{Blockchain, Blockorigin};
+use crate::blockchain::blockchain::{Blockchain, Blockorigin, ReorgEvent};
+use crate::blockchain::validation::check_block;
 use std::borrow::Borrow;
 use std::thread;
 use std::sync::{Arc, Mutex};
@@ -39,6 +40,12 @@ pub fn new(
 
 impl Context {
     pub fn start(self) {
+        let events = self.blockchain.lock().unwrap().subscribe();
+        let cloned = self.clone();
+        thread::spawn(move || {
+            cloned.reorg_loop(events);
+            warn!("Reorg listener exited");
+        });
         let num_worker = self.num_worker;
         for i in 0..num_worker {
             let cloned = self.clone();
@@ -49,6 +56,15 @@ impl Context {
         }
     }
     
+    /// Keep the mempool in line with the tip
+    fn reorg_loop(&self, events: channel::Receiver<ReorgEvent>) {
+        for event in events.iter() {
+            let blockchain = self.blockchain.lock().unwrap();
+            let mut mempool = self.mempool.lock().unwrap();
+            mempool.handle_reorg(&event, &blockchain);
+        }
+    }
+
     fn worker_loop(&self) {
         loop {
             let msg = self.msg_chan.recv().unwrap();
@@ -65,7 +81,7 @@ impl Context {
                 Message::NewBlockHashes(hash_vec)=>{
                     info!("Get new block hashes! {:?}",hash_vec);
                     let blockchain=self.blockchain.lock().unwrap();
-                    let missed_hashes:Vec<_>=hash_vec.into_iter().filter(|hash| !blockchain.contain_block(hash)).collect();
+                    let missed_hashes:Vec<_>=hash_vec.into_iter().filter(|hash| !blockchain.contain_block(hash) && !blockchain.contain_orphan(hash)).collect();
                     if !missed_hashes.is_empty()
                     {
                         peer.write(Message::GetBlocks(missed_hashes));
@@ -86,25 +102,38 @@ impl Context {
                     let now_time=SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
                     let mut blockchain=self.blockchain.lock().unwrap();
                     let mut relay_hashes:Vec<H256>=Vec::new();
-                    let mut missed_hashes:Vec<H256>=Vec::new();
                     let mut mempool=self.mempool.lock().unwrap();
                     for block in block_vec{
                         blockchain.hash_to_origin.entry(block.hash()).or_insert(Blockorigin::Recieved { delay_ms:now_time - block.header.timestamp });
-                        if blockchain.contain_block(&block.hash()){
-                            continue;
-                        }
-                        if !blockchain.pow_validity_check(&block){
+                        let hash=block.hash();
+                        if blockchain.contain_block(&hash) || blockchain.contain_orphan(&hash) || blockchain.is_invalid(&hash){
                             continue;
                         }
                         if !blockchain.parent_check(&block){
+                            if blockchain.is_invalid(&block.header.parent){
+                                blockchain.mark_invalid(&hash);
+                                continue;
+                            }
+                            // only the checks that do not need the parent can be done for now
+                            if !blockchain.pow_validity_check(&block) || check_block(&block,blockchain.config(),&blockchain.chain_id()).is_err(){
+                                continue;
+                            }
                             blockchain.add_to_orphans(&block);
-                            missed_hashes.push(block.header.parent);
+                            continue;
+                        }
+                        if let Err(e)=blockchain.validate_block(&block){
+                            info!("Rejected block {}: {}",hash,e);
+                            if e.marks_invalid(){
+                                blockchain.mark_invalid(&hash);
+                            }
                             continue;
                         }
                         blockchain.insert_all(&block, &mut relay_hashes);
                         //remove transaction in mempool
                         mempool.remove_transaction(block.get_content());
                     }
+                    // ask again for every parent still missing, not only the ones this message revealed
+                    let missed_hashes=blockchain.missing_parents();
                     if !missed_hashes.is_empty(){
                         peer.write(Message::GetBlocks(missed_hashes));
                     }
@@ -127,7 +156,7 @@ impl Context {
                     }
                 }
                 Message::GetTransactions(hash_vec)=>{
-                    let mut transactions:Vec<SignedTransaction>=Vec::new();
+                    let mut transactions:Vec<LedgerTransaction>=Vec::new();
                     let blockchain=self.blockchain.lock().unwrap();
                     let mempool=self.mempool.lock().unwrap();
                     for hash in hash_vec.iter(){
@@ -150,13 +179,21 @@ impl Context {
                     let blockchain=self.blockchain.lock().unwrap();
                     let mut mempool=self.mempool.lock().unwrap();
                     for trans in trans_vec.iter(){
-                        let cur_state=blockchain.get_tip_state();
-                        if trans.verify_by_state(&cur_state){
-                            mempool.insert(trans.clone());
-                            info!("Received a new valid transactions and its hash is {:?}",trans.hash());
-                            new_hashes.push(trans.hash());//get new hashes
+                        let cur_state=blockchain.tip_state();
+                        match mempool.insert(trans.clone(),cur_state){
+                            Ok(replaced)=>{
+                                info!("Received a new valid transactions and its hash is {:?}",trans.hash());
+                                if let Some(replaced)=replaced{
+                                    info!("It replaces {:?}",replaced.hash());
+                                }
+                                new_hashes.push(trans.hash());//get new hashes
+                            }
+                            Err(e)=>debug!("Rejected transaction {:?}: {}",trans.hash(),e),
                         }
                     }
+                    if !new_hashes.is_empty(){
+                        self.server.broadcast(Message::NewTransactionHashes(new_hashes));
+                    }
                 }
             }
         }
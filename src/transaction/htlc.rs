@@ -0,0 +1,64 @@
+//! Hash time-locked transfers, for atomic swaps.
+//!
+//! A `Lock` transaction moves its value into an escrow instead of to the receiver. Until the
+//! timeout height the receiver can take it with a `Claim` that reveals the preimage of the
+//! hashlock; after it, the sender can take it back with a `Refund`. Two parties swapping across
+//! two chains lock behind the same hashlock, the side that knows the preimage with the later
+//! timeout, so that claiming on one chain reveals what the other party needs to claim on the other.
+
+use crate::api::address::H160 as Address;
+use crate::basic::amount::Amount;
+use crate::crypto::hash::H256;
+use crate::transaction::transaction::{TransactionError, TransactionKind};
+use serde::{Deserialize, Serialize};
+
+/// Value locked by a `Lock` transaction, which identifies it by the hash of its raw transaction
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Escrow {
+    pub sender: Address,
+    pub receiver: Address,
+    pub value: Amount,
+    pub hashlock: H256,
+    /// The last height where the receiver can claim; the sender can refund from the one after
+    pub timeout: u64,
+}
+
+/// The hashlock that `preimage` opens
+pub fn hashlock(preimage: &[u8]) -> H256 {
+    ring::digest::digest(&ring::digest::SHA256, preimage).into()
+}
+
+impl Escrow {
+    /// Whether the receiver can still claim in a block at `height`
+    pub fn claimable_at(&self, height: u64) -> bool {
+        height <= self.timeout
+    }
+
+    /// Check that a claim or refund of `kind` from `sender` can release the escrow in a block at
+    /// `height`: a claim by the receiver with the preimage up to the timeout, a refund by the
+    /// sender after it.
+    pub fn check_release(&self, sender: Address, kind: &TransactionKind, height: u64) -> Result<(), TransactionError> {
+        match kind {
+            TransactionKind::Claim { preimage, .. } => {
+                if sender != self.receiver {
+                    return Err(TransactionError::NotEscrowParty);
+                }
+                if !self.claimable_at(height) {
+                    return Err(TransactionError::EscrowTimedOut { timeout: self.timeout, height });
+                }
+                if hashlock(preimage) != self.hashlock {
+                    return Err(TransactionError::BadPreimage);
+                }
+            }
+            _ => {
+                if sender != self.sender {
+                    return Err(TransactionError::NotEscrowParty);
+                }
+                if self.claimable_at(height) {
+                    return Err(TransactionError::EscrowLocked { timeout: self.timeout, height });
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,2 +1,7 @@
+pub mod htlc;
+pub mod multisig;
+pub mod script;
+pub mod token;
 pub mod transaction;
-pub mod transaction_generator;
\ No newline at end of file
+pub mod transaction_generator;
+pub mod utxo;
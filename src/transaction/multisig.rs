@@ -0,0 +1,140 @@
+//! M-of-N multisig accounts.
+//!
+//! A multisig address is the hash of its threshold and its sorted set of keys, so it commits to
+//! both. A transaction from it carries the policy and the signatures of at least `threshold` of
+//! the keys; anyone holding one of the keys can add their signature to a partly signed one.
+
+use crate::api::address::H160 as Address;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+
+/// The most keys a multisig address can have
+pub const MAX_MULTISIG_KEYS: usize = 16;
+
+/// Keeps multisig addresses apart from the addresses of single keys
+const MULTISIG_ADDRESS_DOMAIN: &[u8] = b"multisig";
+
+/// Which keys own a multisig address and how many of them have to sign
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MultisigPolicy {
+    pub threshold: u8,
+    pub pub_keys: Vec<Vec<u8>>, //sorted, without duplicates
+}
+
+/// A multisig policy and the signatures made so far, by the position of the key in the policy
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MultisigWitness {
+    pub policy: MultisigPolicy,
+    pub signatures: Vec<(u8, Vec<u8>)>, //sorted by key position
+}
+
+impl MultisigPolicy {
+    /// A policy where `threshold` of `pub_keys` have to sign, in any order.
+    /// `None` if the threshold is 0 or above the number of distinct keys, or there are too many keys.
+    pub fn new(threshold: u8, mut pub_keys: Vec<Vec<u8>>) -> Option<Self> {
+        pub_keys.sort();
+        pub_keys.dedup();
+        let policy = MultisigPolicy { threshold, pub_keys };
+        if policy.is_valid() {
+            Some(policy)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the policy could come out of `new`; a received one may not
+    pub fn is_valid(&self) -> bool {
+        self.threshold > 0
+            && self.threshold as usize <= self.pub_keys.len()
+            && self.pub_keys.len() <= MAX_MULTISIG_KEYS
+            && self.pub_keys.windows(2).all(|pair| pair[0] < pair[1])
+    }
+
+    /// The address the policy owns
+    pub fn address(&self) -> Address {
+        let bytes = bincode::serialize(&(MULTISIG_ADDRESS_DOMAIN, self.threshold, &self.pub_keys)).unwrap();
+        let digest = ring::digest::digest(&ring::digest::SHA256, &bytes);
+        let mut address = [0; 20];
+        address.copy_from_slice(&digest.as_ref()[12..]);
+        address.into()
+    }
+
+    /// The position of a key in the policy
+    pub fn position(&self, pub_key: &[u8]) -> Option<u8> {
+        self.pub_keys.iter().position(|key| key[..] == *pub_key).map(|position| position as u8)
+    }
+}
+
+impl MultisigWitness {
+    /// A witness without signatures yet
+    pub fn new(policy: MultisigPolicy) -> Self {
+        MultisigWitness { policy, signatures: Vec::new() }
+    }
+
+    /// Sign `message` with `key`, replacing the key's earlier signature.
+    /// Returns `false` and changes nothing if the key is not in the policy.
+    pub fn sign(&mut self, message: &[u8], key: &Ed25519KeyPair) -> bool {
+        let position = match self.policy.position(key.public_key().as_ref()) {
+            Some(position) => position,
+            None => return false,
+        };
+        let signature = key.sign(message).as_ref().to_vec();
+        match self.signatures.binary_search_by_key(&position, |(position, _)| *position) {
+            Ok(index) => self.signatures[index].1 = signature,
+            Err(index) => self.signatures.insert(index, (position, signature)),
+        }
+        true
+    }
+
+    /// How many more keys have to sign
+    pub fn missing_signatures(&self) -> usize {
+        (self.policy.threshold as usize).saturating_sub(self.signatures.len())
+    }
+
+    /// Whether the policy is valid and at least `threshold` different keys of it signed `message`
+    pub fn verify(&self, message: &[u8]) -> bool {
+        let keys = &self.policy.pub_keys;
+        self.policy.is_valid()
+            && self.missing_signatures() == 0
+            && self.signatures.windows(2).all(|pair| pair[0].0 < pair[1].0)
+            && self.signatures.iter().all(|(position, signature)| match keys.get(*position as usize) {
+                Some(key) => UnparsedPublicKey::new(&ED25519, &key[..]).verify(message, signature).is_ok(),
+                None => false,
+            })
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+
+    #[test]
+    fn threshold_of_distinct_keys() {
+        let keys: Vec<Ed25519KeyPair> = (0..3).map(|_| key_pair::random()).collect();
+        let pub_keys: Vec<Vec<u8>> = keys.iter().map(|key| key.public_key().as_ref().to_vec()).collect();
+        assert_eq!(MultisigPolicy::new(0, pub_keys.clone()), None);
+        assert_eq!(MultisigPolicy::new(3, vec![pub_keys[0].clone(), pub_keys[0].clone(), pub_keys[1].clone()]), None);
+        // the address does not depend on the order of the keys, but on the threshold
+        let policy = MultisigPolicy::new(2, pub_keys.clone()).unwrap();
+        let reversed = MultisigPolicy::new(2, pub_keys.iter().rev().cloned().collect()).unwrap();
+        assert_eq!(policy.address(), reversed.address());
+        assert_ne!(policy.address(), MultisigPolicy::new(3, pub_keys).unwrap().address());
+
+        let mut witness = MultisigWitness::new(policy);
+        assert!(witness.sign(b"message", &keys[2]));
+        assert!(!witness.sign(b"message", &key_pair::random()));
+        assert_eq!(witness.missing_signatures(), 1);
+        assert!(!witness.verify(b"message"));
+        // signing twice with the same key does not count twice
+        assert!(witness.sign(b"message", &keys[2]));
+        assert!(!witness.verify(b"message"));
+        assert!(witness.sign(b"message", &keys[0]));
+        assert!(witness.verify(b"message"));
+        assert!(!witness.verify(b"other message"));
+
+        let mut repeated = witness.clone();
+        repeated.signatures[1] = repeated.signatures[0].clone();
+        assert!(!repeated.verify(b"message"));
+    }
+}
@@ -0,0 +1,329 @@
+//! A small stack-based script language for spending conditions.
+//!
+//! A script address is the hash of a locking script. A transaction from it carries the script
+//! and the arguments that unlock it: the arguments go on the stack, the script runs, and the
+//! transaction is valid if the script ends with true on top of the stack. Scripts have no loops
+//! and every opcode is charged against a fixed budget, so they always end, and their result
+//! only depends on the transaction and the block it goes into.
+//!
+//! Items on the stack are byte strings. An item is true if any of its bytes is not zero, and
+//! opcodes push `[1]` for true and `[]` for false. Numbers are 8 bytes, little-endian.
+
+use crate::api::address::H160 as Address;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+
+/// How much a script may spend on running
+pub const SCRIPT_BUDGET: u64 = 1000;
+/// The most items the stack can hold
+pub const MAX_STACK_ITEMS: usize = 64;
+/// The largest item that can be pushed, or given as an argument
+pub const MAX_ITEM_SIZE: usize = 520;
+
+/// Keeps script addresses apart from other kinds of addresses
+const SCRIPT_ADDRESS_DOMAIN: &[u8] = b"script";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Push the bytes
+    Push(Vec<u8>),
+    /// Push a copy of the top item
+    Dup,
+    /// Remove the top item
+    Drop,
+    /// Swap the two top items
+    Swap,
+    /// Pop a public key, then a signature; push whether it signs the transaction
+    CheckSig,
+    /// Pop an item and push its SHA-256 hash
+    Sha256,
+    /// Pop two items and push whether they are equal
+    Equal,
+    /// Pop a height and push whether the block is at or above it
+    CheckHeight,
+    /// Pop a time in milliseconds and push whether the block's timestamp is at or after it
+    CheckTime,
+    /// Pop an item and push whether it is false
+    Not,
+    /// Pop two items and push whether both are true
+    And,
+    /// Pop two items and push whether either is true
+    Or,
+    /// Pop an item and fail unless it is true
+    Verify,
+}
+
+impl Op {
+    /// Push `n` as a number
+    pub fn number(n: u64) -> Op {
+        Op::Push(n.to_le_bytes().to_vec())
+    }
+
+    /// What running the opcode takes out of the budget
+    fn cost(&self) -> u64 {
+        match self {
+            Op::CheckSig => 50,
+            Op::Sha256 => 10,
+            _ => 1,
+        }
+    }
+}
+
+/// A locking script
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Script(pub Vec<Op>);
+
+/// A script and the arguments that unlock it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScriptWitness {
+    pub script: Script,
+    pub args: Vec<Vec<u8>>,
+}
+
+/// What a script can check besides its arguments
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptContext<'a> {
+    /// The bytes a signature has to sign
+    pub message: &'a [u8],
+    /// The height of the block the transaction goes into
+    pub height: u64,
+    /// The timestamp of that block
+    pub time: u128,
+}
+
+/// Why a script failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptError {
+    /// An opcode needs more items than are on the stack.
+    StackUnderflow,
+    /// The stack would hold more than `MAX_STACK_ITEMS`.
+    StackOverflow,
+    /// An item or argument is larger than `MAX_ITEM_SIZE`.
+    ItemTooLarge,
+    /// A number is not 8 bytes long.
+    BadNumber,
+    /// The script ran out of budget.
+    BudgetExceeded,
+    /// `Verify` popped false.
+    VerifyFailed,
+    /// The script ended with an empty stack or false on top.
+    False,
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ScriptError::StackUnderflow => write!(f, "stack underflow"),
+            ScriptError::StackOverflow => write!(f, "more than {} items on the stack", MAX_STACK_ITEMS),
+            ScriptError::ItemTooLarge => write!(f, "item larger than {} bytes", MAX_ITEM_SIZE),
+            ScriptError::BadNumber => write!(f, "a number is not 8 bytes"),
+            ScriptError::BudgetExceeded => write!(f, "ran out of budget"),
+            ScriptError::VerifyFailed => write!(f, "verify failed"),
+            ScriptError::False => write!(f, "the script ended false"),
+        }
+    }
+}
+
+impl Script {
+    /// The address the script locks
+    pub fn address(&self) -> Address {
+        let bytes = bincode::serialize(&(SCRIPT_ADDRESS_DOMAIN, self)).unwrap();
+        let digest = ring::digest::digest(&ring::digest::SHA256, &bytes);
+        let mut address = [0; 20];
+        address.copy_from_slice(&digest.as_ref()[12..]);
+        address.into()
+    }
+
+    /// Run the script on `args` and check that it ends true
+    pub fn run(&self, args: &[Vec<u8>], context: &ScriptContext) -> Result<(), ScriptError> {
+        let mut machine = Machine { stack: Vec::new(), budget: SCRIPT_BUDGET };
+        for arg in args {
+            machine.push(arg.clone())?;
+        }
+        for op in self.0.iter() {
+            machine.step(op, context)?;
+        }
+        match machine.stack.last() {
+            Some(top) if is_true(top) => Ok(()),
+            _ => Err(ScriptError::False),
+        }
+    }
+}
+
+fn is_true(item: &[u8]) -> bool {
+    item.iter().any(|byte| *byte != 0)
+}
+
+fn boolean(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        Vec::new()
+    }
+}
+
+fn number(item: &[u8]) -> Result<u64, ScriptError> {
+    let mut bytes = [0; 8];
+    if item.len() != bytes.len() {
+        return Err(ScriptError::BadNumber);
+    }
+    bytes.copy_from_slice(item);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+struct Machine {
+    stack: Vec<Vec<u8>>,
+    budget: u64,
+}
+
+impl Machine {
+    fn push(&mut self, item: Vec<u8>) -> Result<(), ScriptError> {
+        if item.len() > MAX_ITEM_SIZE {
+            return Err(ScriptError::ItemTooLarge);
+        }
+        if self.stack.len() >= MAX_STACK_ITEMS {
+            return Err(ScriptError::StackOverflow);
+        }
+        self.stack.push(item);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Vec<u8>, ScriptError> {
+        self.stack.pop().ok_or(ScriptError::StackUnderflow)
+    }
+
+    fn step(&mut self, op: &Op, context: &ScriptContext) -> Result<(), ScriptError> {
+        self.budget = self.budget.checked_sub(op.cost()).ok_or(ScriptError::BudgetExceeded)?;
+        match op {
+            Op::Push(item) => self.push(item.clone())?,
+            Op::Dup => {
+                let top = self.stack.last().ok_or(ScriptError::StackUnderflow)?.clone();
+                self.push(top)?;
+            }
+            Op::Drop => {
+                self.pop()?;
+            }
+            Op::Swap => {
+                let (top, below) = (self.pop()?, self.pop()?);
+                self.push(top)?;
+                self.push(below)?;
+            }
+            Op::CheckSig => {
+                let (pub_key, signature) = (self.pop()?, self.pop()?);
+                let valid = UnparsedPublicKey::new(&ED25519, &pub_key[..]).verify(context.message, &signature).is_ok();
+                self.push(boolean(valid))?;
+            }
+            Op::Sha256 => {
+                let item = self.pop()?;
+                self.push(ring::digest::digest(&ring::digest::SHA256, &item).as_ref().to_vec())?;
+            }
+            Op::Equal => {
+                let (a, b) = (self.pop()?, self.pop()?);
+                self.push(boolean(a == b))?;
+            }
+            Op::CheckHeight => {
+                let height = number(&self.pop()?)?;
+                self.push(boolean(context.height >= height))?;
+            }
+            Op::CheckTime => {
+                let time = number(&self.pop()?)?;
+                self.push(boolean(context.time >= time as u128))?;
+            }
+            Op::Not => {
+                let item = self.pop()?;
+                self.push(boolean(!is_true(&item)))?;
+            }
+            Op::And | Op::Or => {
+                let (a, b) = (is_true(&self.pop()?), is_true(&self.pop()?));
+                self.push(boolean(if *op == Op::And { a && b } else { a || b }))?;
+            }
+            Op::Verify => {
+                if !is_true(&self.pop()?) {
+                    return Err(ScriptError::VerifyFailed);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+    use ring::signature::KeyPair;
+
+    fn context(height: u64, time: u128) -> ScriptContext<'static> {
+        ScriptContext { message: b"message", height, time }
+    }
+
+    /// A script, its arguments and what running it gives
+    type Vector = (Vec<Op>, Vec<Vec<u8>>, Result<(), ScriptError>);
+
+    fn run(ops: Vec<Op>, args: Vec<Vec<u8>>) -> Result<(), ScriptError> {
+        Script(ops).run(&args, &context(10, 5000))
+    }
+
+    #[test]
+    fn opcode_vectors() {
+        let key = key_pair::random();
+        let pub_key = key.public_key().as_ref().to_vec();
+        let signature = key.sign(b"message").as_ref().to_vec();
+        let other_signature = key.sign(b"other").as_ref().to_vec();
+        let preimage = b"secret".to_vec();
+        let hash = ring::digest::digest(&ring::digest::SHA256, &preimage).as_ref().to_vec();
+        let (t, f) = (Op::Push(vec![1]), Op::Push(vec![]));
+        let vectors: Vec<Vector> = vec![
+            // stack
+            (vec![], vec![], Err(ScriptError::False)),
+            (vec![t.clone()], vec![], Ok(())),
+            (vec![Op::Push(vec![0, 0])], vec![], Err(ScriptError::False)),
+            (vec![Op::Push(vec![0; MAX_ITEM_SIZE + 1])], vec![], Err(ScriptError::ItemTooLarge)),
+            (vec![], vec![vec![0; MAX_ITEM_SIZE + 1]], Err(ScriptError::ItemTooLarge)),
+            (vec![Op::Dup], vec![], Err(ScriptError::StackUnderflow)),
+            (vec![t.clone(), Op::Dup, Op::And], vec![], Ok(())),
+            (vec![Op::Drop], vec![vec![1], vec![]], Ok(())),
+            (vec![Op::Drop], vec![vec![1]], Err(ScriptError::False)),
+            (vec![Op::Swap], vec![vec![1], vec![]], Ok(())),
+            (vec![Op::Swap], vec![vec![1]], Err(ScriptError::StackUnderflow)),
+            (vec![Op::Dup; MAX_STACK_ITEMS], vec![vec![1]], Err(ScriptError::StackOverflow)),
+            // signatures
+            (vec![Op::Push(pub_key.clone()), Op::CheckSig], vec![signature.clone()], Ok(())),
+            (vec![Op::Push(pub_key.clone()), Op::CheckSig], vec![other_signature], Err(ScriptError::False)),
+            (vec![Op::Push(pub_key.clone()), Op::CheckSig], vec![], Err(ScriptError::StackUnderflow)),
+            // hashes
+            (vec![Op::Sha256, Op::Push(hash.clone()), Op::Equal], vec![preimage.clone()], Ok(())),
+            (vec![Op::Sha256, Op::Push(hash), Op::Equal], vec![b"guess".to_vec()], Err(ScriptError::False)),
+            (vec![Op::Equal], vec![vec![1, 2], vec![1, 2]], Ok(())),
+            (vec![Op::Equal], vec![vec![1, 2], vec![2, 1]], Err(ScriptError::False)),
+            // locks
+            (vec![Op::number(10), Op::CheckHeight], vec![], Ok(())),
+            (vec![Op::number(11), Op::CheckHeight], vec![], Err(ScriptError::False)),
+            (vec![Op::Push(vec![10]), Op::CheckHeight], vec![], Err(ScriptError::BadNumber)),
+            (vec![Op::number(5000), Op::CheckTime], vec![], Ok(())),
+            (vec![Op::number(5001), Op::CheckTime], vec![], Err(ScriptError::False)),
+            // logic
+            (vec![f.clone(), Op::Not], vec![], Ok(())),
+            (vec![t.clone(), Op::Not], vec![], Err(ScriptError::False)),
+            (vec![t.clone(), t.clone(), Op::And], vec![], Ok(())),
+            (vec![t.clone(), f.clone(), Op::And], vec![], Err(ScriptError::False)),
+            (vec![f.clone(), t.clone(), Op::Or], vec![], Ok(())),
+            (vec![f.clone(), f.clone(), Op::Or], vec![], Err(ScriptError::False)),
+            (vec![t.clone(), Op::Verify, t.clone()], vec![], Ok(())),
+            (vec![f.clone(), Op::Verify, t.clone()], vec![], Err(ScriptError::VerifyFailed)),
+            // budget
+            (vec![vec![Op::Push(pub_key.clone()), Op::CheckSig, Op::Drop]; 20].concat(), vec![vec![]; 20], Err(ScriptError::BudgetExceeded)),
+            (vec![vec![t, Op::Drop]; 500].concat(), vec![vec![1]], Ok(())),
+        ];
+        for (index, (ops, args, expected)) in vectors.into_iter().enumerate() {
+            assert_eq!(run(ops, args), expected, "vector {}", index);
+        }
+    }
+
+    #[test]
+    fn budget_and_address() {
+        let ops = vec![vec![Op::Push(vec![1]), Op::Drop]; 500].concat();
+        assert_eq!(run([ops.clone(), vec![Op::Dup]].concat(), vec![vec![1]]), Err(ScriptError::BudgetExceeded));
+        assert_ne!(Script(ops).address(), Script(vec![Op::Push(vec![1]), Op::Drop]).address());
+    }
+}
@@ -0,0 +1,26 @@
+//! Fungible tokens issued on the account ledger.
+//!
+//! A `TokenCreate` transaction issues a token named by the hash of its raw transaction and gives
+//! the initial supply to its sender, who becomes the issuer. Only the issuer can `TokenMint` more
+//! of it, and any holder can `TokenTransfer` some to another address. Token amounts are units of
+//! the token's own, apart from the native coin, which still pays the fees.
+
+use crate::api::address::H160 as Address;
+use serde::{Deserialize, Serialize};
+
+/// A token issued by a `TokenCreate` transaction
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    /// The sender of the create transaction, the only one who can mint
+    pub issuer: Address,
+    /// The units created and minted so far
+    pub supply: u64,
+}
+
+impl Token {
+    /// The token after minting `amount` more, or `None` if the supply would overflow
+    pub fn minted(&self, amount: u64) -> Option<Token> {
+        let supply = self.supply.checked_add(amount)?;
+        Some(Token { issuer: self.issuer, supply })
+    }
+}
@@ -1,4 +1,3 @@
-use log::{debug, info};
 use serde::{Serialize,Deserialize};
 use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters,UnparsedPublicKey,ED25519};
 use ring::digest::{digest,SHA256};
@@ -6,22 +5,64 @@ use crate::crypto::hash::{H256, Hashable};
 use rand::{Rng, thread_rng};
 use crate::api::address::H160 as Address;
 use crate::crypto::key_pair;
-use crate::basic::state::State;
+use crate::basic::amount::{Amount, MAX_SUPPLY};
+use crate::basic::ledger::LedgerModel;
+use super::multisig::{MultisigPolicy, MultisigWitness};
+use super::script::{ScriptError, ScriptWitness};
+use super::utxo::UtxoTransaction;
 const ADDR_SIZE:usize=20;
-#[derive(Serialize, Deserialize,Debug,Default,Clone)]
+/// The signing domain of transactions
+pub const TRANSACTION_DOMAIN:&[u8]=b"transaction";
+/// The most bytes a memo can have
+pub const MAX_MEMO_SIZE:usize=80;
+#[derive(Serialize, Deserialize,Debug,Default,Clone,PartialEq,Eq)]
 pub struct Transaction 
 {
    pub sender:Address,
    pub nonce:usize,
    pub receiver:Address,
-   pub value:usize,
+   pub value:Amount,
+   pub fee:Amount,//paid to the miner of the block that includes the transaction
+   pub valid_until_height:Option<u64>,//the last block height that may include the transaction
+   pub kind:TransactionKind,
+   pub memo:Vec<u8>,//free bytes for the receiver, e.g. an invoice id; signed and paid for by size like the rest
 }
-#[derive(Serialize, Deserialize, Debug,Default,Clone)]
+/// What a transaction does besides paying the fee
+#[derive(Serialize, Deserialize,Debug,Default,Clone,PartialEq,Eq)]
+pub enum TransactionKind
+{
+    /// Pay `value` to `receiver`
+    #[default]
+    Transfer,
+    /// Lock `value` for `receiver` behind a hashlock until the `timeout` height, see `Escrow`
+    Lock{hashlock:H256,timeout:u64},
+    /// Take the value of an escrow for its receiver by revealing the hashlock's preimage.
+    /// `receiver` and `value` are not used.
+    Claim{escrow:H256,preimage:Vec<u8>},
+    /// Take the value of an escrow back for its sender after the timeout.
+    /// `receiver` and `value` are not used.
+    Refund{escrow:H256},
+    /// Pay every output, all or none. `receiver` and `value` are not used.
+    Batch{outputs:Vec<(Address,Amount)>},
+    /// Issue a token with `supply` units for the sender, named by the raw transaction's hash, see `Token`.
+    /// `receiver` and `value` are not used.
+    TokenCreate{supply:u64},
+    /// Pay `amount` units of a token to `receiver`. `value` is not used.
+    TokenTransfer{token:H256,amount:u64},
+    /// Issue `amount` more units of a token to `receiver`, which only its issuer can. `value` is not used.
+    TokenMint{token:H256,amount:u64},
+}
+/// A transaction and its signature. A transaction from a multisig address carries the
+/// signatures in `multisig` instead, and one from a script address the script and its
+/// arguments in `script`; `signature` and `pub_key` are empty then.
+#[derive(Serialize, Deserialize, Debug,Default,Clone,PartialEq,Eq)]
 pub struct SignedTransaction
 {
     pub trans_raw:Transaction,
     pub signature:Vec<u8>,
     pub pub_key:Vec<u8>,
+    pub multisig:Option<MultisigWitness>,
+    pub script:Option<Box<ScriptWitness>>,
 }
 impl Hashable for SignedTransaction {
     fn hash(&self) -> H256 {
@@ -29,21 +70,131 @@ impl Hashable for SignedTransaction {
         ring::digest::digest(&ring::digest::SHA256, &bytes).into()
     }
 }
+impl Transaction{
+    /// What the sender pays: the value plus the fee, or `None` if that is above the supply.
+    /// Claims, refunds and token transactions only pay the fee, and batches the sum of their outputs plus the fee.
+    pub fn cost(&self)->Option<Amount>{
+        match &self.kind{
+            TransactionKind::Transfer|TransactionKind::Lock{..}=>self.value.checked_add(self.fee),
+            TransactionKind::Claim{..}|TransactionKind::Refund{..}
+                |TransactionKind::TokenCreate{..}|TransactionKind::TokenTransfer{..}|TransactionKind::TokenMint{..}=>Some(self.fee),
+            TransactionKind::Batch{outputs}=>Amount::checked_sum(outputs.iter().map(|(_,value)| *value).chain(Some(self.fee))),
+        }
+    }
+    /// Check that the memo is at most `MAX_MEMO_SIZE` bytes
+    pub fn check_memo(&self)->Result<(),TransactionError>{
+        if self.memo.len()>MAX_MEMO_SIZE{
+            return Err(TransactionError::MemoTooLarge{size:self.memo.len()});
+        }
+        Ok(())
+    }
+}
 impl Hashable for Transaction{
     fn hash(&self) ->H256{
         let bytes=bincode::serialize(&self).unwrap();
         ring::digest::digest(&ring::digest::SHA256,&bytes).into()
     }
 }
-/// Create digital signature of a transaction
-pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
+/// Why a transaction cannot be applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    BadSignature,
+    UnknownSender,
+    BadNonce { expected: usize, got: usize },
+    /// The balance does not cover the value plus the fee.
+    InsufficientBalance { balance: Amount, cost: Amount },
+    /// An amount or a balance would go above the total supply.
+    Overflow,
+    /// The transaction is only valid up to a height below the block's.
+    Expired { valid_until: u64, height: u64 },
+    /// The public key that signed the transaction is not the sender's.
+    NotOwner,
+    /// The transaction is of the other ledger model than the chain's.
+    WrongLedger,
+    /// Input `index` spends an output that does not exist or is spent already.
+    MissingInput { index: usize },
+    /// The inputs do not add up to the outputs plus the fee.
+    Unbalanced { inputs: Amount, spent: Amount },
+    /// No escrow with this id is locked.
+    UnknownEscrow,
+    /// The sender is not the escrow's receiver for a claim, or its sender for a refund.
+    NotEscrowParty,
+    /// The preimage does not open the escrow's hashlock.
+    BadPreimage,
+    /// The escrow can no longer be claimed at the block's height.
+    EscrowTimedOut { timeout: u64, height: u64 },
+    /// The escrow cannot be refunded before its timeout has passed.
+    EscrowLocked { timeout: u64, height: u64 },
+    /// The sender's script failed.
+    Script(ScriptError),
+    /// No token with this id was created.
+    UnknownToken,
+    /// A token with the id of this create transaction exists already.
+    TokenExists,
+    /// Only the issuer of a token can mint it.
+    NotIssuer,
+    /// The sender holds fewer units of the token than it sends.
+    InsufficientTokens { balance: u64, amount: u64 },
+    /// The memo has more than `MAX_MEMO_SIZE` bytes.
+    MemoTooLarge { size: usize },
+}
+
+impl std::fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TransactionError::BadSignature => write!(f, "bad signature"),
+            TransactionError::UnknownSender => write!(f, "unknown sender"),
+            TransactionError::BadNonce { expected, got } => write!(f, "nonce {} but expected {}", got, expected),
+            TransactionError::InsufficientBalance { balance, cost } => {
+                write!(f, "spends {} but the balance is {}", cost, balance)
+            }
+            TransactionError::Expired { valid_until, height } => {
+                write!(f, "valid until height {} but the block is at {}", valid_until, height)
+            }
+            TransactionError::Overflow => write!(f, "amount above the total supply"),
+            TransactionError::NotOwner => write!(f, "signed by a key that does not own the sender"),
+            TransactionError::WrongLedger => write!(f, "not a transaction of the chain's ledger model"),
+            TransactionError::MissingInput { index } => write!(f, "input {} spends a missing output", index),
+            TransactionError::Unbalanced { inputs, spent } => {
+                write!(f, "inputs of {} but outputs and fee of {}", inputs, spent)
+            }
+            TransactionError::UnknownEscrow => write!(f, "unknown escrow"),
+            TransactionError::NotEscrowParty => write!(f, "the sender cannot release the escrow"),
+            TransactionError::BadPreimage => write!(f, "the preimage does not open the hashlock"),
+            TransactionError::EscrowTimedOut { timeout, height } => {
+                write!(f, "escrow claimable until height {} but the block is at {}", timeout, height)
+            }
+            TransactionError::EscrowLocked { timeout, height } => {
+                write!(f, "escrow locked until height {} but the block is at {}", timeout, height)
+            }
+            TransactionError::Script(error) => write!(f, "script failed: {}", error),
+            TransactionError::UnknownToken => write!(f, "unknown token"),
+            TransactionError::TokenExists => write!(f, "the token exists already"),
+            TransactionError::NotIssuer => write!(f, "only the issuer can mint the token"),
+            TransactionError::InsufficientTokens { balance, amount } => {
+                write!(f, "sends {} units of the token but holds {}", amount, balance)
+            }
+            TransactionError::MemoTooLarge { size } => {
+                write!(f, "memo of {} bytes but at most {} are allowed", size, MAX_MEMO_SIZE)
+            }
+        }
+    }
+}
+
+/// The bytes signed for a transaction on the chain `chain_id`
+pub fn signing_message(t: &Transaction, chain_id: &H256) -> Vec<u8> {
     let bytes=bincode::serialize(&t).unwrap();
-    key.sign(&bytes)
+    key_pair::signing_message(TRANSACTION_DOMAIN, chain_id, &bytes)
+}
+
+/// Create digital signature of a transaction, valid on the chain `chain_id` only
+pub fn sign(t: &Transaction, chain_id: &H256, key: &Ed25519KeyPair) -> Signature {
+    key.sign(&signing_message(t, chain_id))
 }
 
 /// Verify digital signature of a transaction, using public key instead of secret key
-pub fn verify(t: &Transaction, public_key: &<Ed25519KeyPair as KeyPair>::PublicKey, signature: &Signature) -> bool {
-    let bytes=bincode::serialize(&t).unwrap();
+pub fn verify(t: &Transaction, chain_id: &H256, public_key: &<Ed25519KeyPair as KeyPair>::PublicKey, signature: &Signature) -> bool {
+    let bytes=signing_message(t, chain_id);
     let pk=UnparsedPublicKey::new(&ED25519,public_key.as_ref().to_vec());
     let res=pk.verify(&bytes, &signature.as_ref().to_vec());
     res.is_ok()
@@ -66,47 +217,152 @@ pub fn generate_random_transaction() -> Transaction {
     }
     let sender_addr=Address::new(sender_addr_ori);
     let receiver_addr=Address::new(receiver_addr_ori);
-    let val:usize=rng.gen();
+    let val=Amount::from_base_units(rng.gen_range(0,MAX_SUPPLY.base_units()));
     let nonce:usize=rng.gen();
-    Transaction { sender: (sender_addr), nonce: (nonce), receiver: (receiver_addr), value: (val) }
+    let fee=Amount::from_base_units(rng.gen_range(0,MAX_SUPPLY.base_units()));
+    Transaction { sender: (sender_addr), nonce: (nonce), receiver: (receiver_addr), value: (val), fee, valid_until_height: None, kind: TransactionKind::Transfer, memo: Vec::new() }
 }
- pub fn generate_random_signed_transaction_with_key(key:&Ed25519KeyPair)->SignedTransaction{
+ pub fn generate_random_signed_transaction_with_key(key:&Ed25519KeyPair,chain_id:&H256)->SignedTransaction{
     let t = generate_random_transaction();
-    let sig=sign(&t,&key);
-    SignedTransaction { trans_raw: (t), signature: (sig.as_ref().to_vec()),pub_key:key.public_key().as_ref().to_vec() }
+    let sig=sign(&t,chain_id,key);
+    SignedTransaction { trans_raw: (t), signature: (sig.as_ref().to_vec()),pub_key:key.public_key().as_ref().to_vec(),multisig:None,script:None }
 }
  impl SignedTransaction {
-    /// Create a new transaction from a raw transaction and a key pair
-    pub fn from_raw(raw: Transaction, key: &Ed25519KeyPair) -> SignedTransaction {
+    /// Create a new transaction for the chain `chain_id` from a raw transaction and a key pair
+    pub fn from_raw(raw: Transaction, chain_id: &H256, key: &Ed25519KeyPair) -> SignedTransaction {
         let pub_key = key.public_key().as_ref().to_vec();
-        let signature = sign(&raw, key).as_ref().to_vec();
-        SignedTransaction { trans_raw:raw, pub_key, signature }
-    }
-    pub fn verify_by_state(&self,now_state:&State)->bool{
-        let sender=self.trans_raw.sender;
-        if !self.verify_signature(){
-            info!("INVALID TRANSACTION!");
-            return false
+        let signature = sign(&raw, chain_id, key).as_ref().to_vec();
+        SignedTransaction { trans_raw:raw, pub_key, signature, multisig:None, script:None }
+    }
+    /// A transaction from the multisig address of `policy` without signatures yet, to be signed with `sign_multisig`
+    pub fn multisig(raw: Transaction, policy: MultisigPolicy) -> SignedTransaction {
+        SignedTransaction { trans_raw:raw, multisig:Some(MultisigWitness::new(policy)), ..SignedTransaction::default() }
+    }
+    /// A transaction from the address of `witness.script`, which the ledger runs on `witness.args`.
+    /// Signatures in the arguments sign `signing_message` of the raw transaction.
+    pub fn scripted(raw: Transaction, witness: ScriptWitness) -> SignedTransaction {
+        SignedTransaction { trans_raw:raw, script:Some(Box::new(witness)), ..SignedTransaction::default() }
+    }
+    /// Add the signature of one of the multisig keys on the chain `chain_id`.
+    /// Returns `false` if this is not a multisig transaction or the key is not one of its keys.
+    pub fn sign_multisig(&mut self, chain_id: &H256, key: &Ed25519KeyPair) -> bool {
+        let message = signing_message(&self.trans_raw, chain_id);
+        match self.multisig.as_mut(){
+            Some(witness) => witness.sign(&message, key),
+            None => false,
         }
-        let accounts=now_state.get_accounts();
-        if !accounts.contains_key(&sender){
-            info!("INVALID TRANSACTION!");
-            return false;
+    }
+    /// The serialized size in bytes, which is what the transaction takes in a block or the mempool
+    pub fn size(&self)->usize{
+        bincode::serialize(&self).unwrap().len()
+    }
+    /// The fee per 1000 bytes
+    pub fn fee_rate(&self)->u64{
+        (self.trans_raw.fee.base_units() as u128*1000/self.size() as u128).min(u64::MAX as u128) as u64
+    }
+    /// Verify the signature of this transaction on the chain `chain_id`.
+    /// A multisig transaction needs valid signatures of at least the threshold of its keys.
+    /// The signatures of a scripted transaction are up to its script, which only the ledger can run.
+    pub fn verify_signature(&self, chain_id: &H256) -> bool {
+        let message = signing_message(&self.trans_raw, chain_id);
+        if let Some(witness) = &self.multisig {
+            return witness.verify(&message);
         }
-        let (sender_nonce,sender_balance)=accounts.get(&sender).unwrap();
-        let value=self.trans_raw.value;
-        if sender_balance>&value && sender_nonce+1==self.trans_raw.nonce{
-            //debug!("verify success!\n");
+        if self.script.is_some() {
             return true;
         }
-        false
-    }
-    /// Verify the signature of this transaction
-    pub fn verify_signature(&self) -> bool {
-        let serialized_raw = bincode::serialize(&self.trans_raw).unwrap();
         let public_key = ring::signature::UnparsedPublicKey::new(
             &ring::signature::ED25519, &self.pub_key[..]);
-        public_key.verify(&serialized_raw, self.signature.as_ref()).is_ok()
+        public_key.verify(&message, self.signature.as_ref()).is_ok()
+    }
+    /// Whether the attached public key, multisig policy or script is the one the sender address is derived from
+    pub fn signed_by_sender(&self) -> bool {
+        let address = match (&self.multisig, &self.script) {
+            (Some(witness), _) => witness.policy.address(),
+            (None, Some(witness)) => witness.script.address(),
+            (None, None) => Address::from_pubkey(&self.pub_key),
+        };
+        address==self.trans_raw.sender
+    }
+}
+/// A transaction of either ledger model. A chain only takes the ones of its own model.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum LedgerTransaction {
+    Account(SignedTransaction),
+    Utxo(UtxoTransaction),
+}
+impl From<SignedTransaction> for LedgerTransaction {
+    fn from(transaction: SignedTransaction) -> Self {
+        LedgerTransaction::Account(transaction)
+    }
+}
+impl From<UtxoTransaction> for LedgerTransaction {
+    fn from(transaction: UtxoTransaction) -> Self {
+        LedgerTransaction::Utxo(transaction)
+    }
+}
+impl Hashable for LedgerTransaction {
+    /// The hash of the transaction itself, whatever its model
+    fn hash(&self) -> H256 {
+        match self {
+            LedgerTransaction::Account(transaction) => transaction.hash(),
+            LedgerTransaction::Utxo(transaction) => transaction.hash(),
+        }
+    }
+}
+impl LedgerTransaction {
+    pub fn model(&self) -> LedgerModel {
+        match self {
+            LedgerTransaction::Account(_) => LedgerModel::Account,
+            LedgerTransaction::Utxo(_) => LedgerModel::Utxo,
+        }
+    }
+    pub fn as_account(&self) -> Option<&SignedTransaction> {
+        match self {
+            LedgerTransaction::Account(transaction) => Some(transaction),
+            LedgerTransaction::Utxo(_) => None,
+        }
+    }
+    pub fn fee(&self) -> Amount {
+        match self {
+            LedgerTransaction::Account(transaction) => transaction.trans_raw.fee,
+            LedgerTransaction::Utxo(transaction) => transaction.fee,
+        }
+    }
+    pub fn valid_until_height(&self) -> Option<u64> {
+        match self {
+            LedgerTransaction::Account(transaction) => transaction.trans_raw.valid_until_height,
+            LedgerTransaction::Utxo(transaction) => transaction.valid_until_height,
+        }
+    }
+    /// The memo of an account transaction; UTXO transactions have none
+    pub fn memo(&self) -> &[u8] {
+        match self {
+            LedgerTransaction::Account(transaction) => &transaction.trans_raw.memo,
+            LedgerTransaction::Utxo(_) => &[],
+        }
+    }
+    /// Check what does not depend on the ledger besides the signatures, which is the memo size
+    pub fn check_memo(&self) -> Result<(), TransactionError> {
+        match self {
+            LedgerTransaction::Account(transaction) => transaction.trans_raw.check_memo(),
+            LedgerTransaction::Utxo(_) => Ok(()),
+        }
+    }
+    /// The serialized size in bytes, which is what the transaction takes in a block or the mempool
+    pub fn size(&self) -> usize {
+        bincode::serialize(&self).unwrap().len()
+    }
+    /// The fee per 1000 bytes
+    pub fn fee_rate(&self) -> u64 {
+        (self.fee().base_units() as u128*1000/self.size() as u128).min(u64::MAX as u128) as u64
+    }
+    /// Verify the signatures on the chain `chain_id`. Who owns what is up to the ledger.
+    pub fn verify_signatures(&self, chain_id: &H256) -> bool {
+        match self {
+            LedgerTransaction::Account(transaction) => transaction.verify_signature(chain_id),
+            LedgerTransaction::Utxo(transaction) => transaction.verify_signatures(chain_id),
+        }
     }
 }
 #[cfg(any(test, test_utilities))]
@@ -117,7 +373,22 @@ mod tests {
     fn sign_verify() {
         let t = generate_random_transaction();
         let key = key_pair::random();
-        let signature = sign(&t, &key);
-        assert!(verify(&t, &(key.public_key()), &signature));
+        let chain_id = generate_random_hash();
+        let signature = sign(&t, &chain_id, &key);
+        assert!(verify(&t, &chain_id, key.public_key(), &signature));
+    }
+
+    #[test]
+    fn signature_is_bound_to_chain() {
+        let key = key_pair::random();
+        let chain_id = generate_random_hash();
+        let transaction = SignedTransaction::from_raw(generate_random_transaction(), &chain_id, &key);
+        assert!(transaction.verify_signature(&chain_id));
+        assert!(!transaction.verify_signature(&generate_random_hash()));
+        // the same bytes signed in another domain do not pass as a transaction
+        let bytes = bincode::serialize(&transaction.trans_raw).unwrap();
+        let other = key.sign(&key_pair::signing_message(b"block", &chain_id, &bytes));
+        let forged = SignedTransaction { signature: other.as_ref().to_vec(), ..transaction };
+        assert!(!forged.verify_signature(&chain_id));
     }
 }
@@ -10,7 +10,8 @@ use crate::crypto::key_pair;
 use crate::network::peer;
 use crate::network::server::Handle as ServerHandle;
 use crate::transaction;
-use crate::transaction::transaction::{generate_random_signed_transaction_with_key, SignedTransaction, Transaction};
+use crate::transaction::transaction::{generate_random_signed_transaction_with_key, LedgerTransaction, SignedTransaction, Transaction, TransactionKind};
+use crate::transaction::utxo::{TxOutput, UtxoTransaction};
 use std::ops::Add;
 use std::sync::atomic::Ordering;
 use std::thread;
@@ -18,14 +19,16 @@ use std::time;
 use std::sync::{Arc, Mutex};
 use crate::basic::mempool::Mempool;
 use crate::network::message::Message;
-use crate::blockchain::blockchain::{Blockchain,Blockorigin};
+use crate::blockchain::blockchain::{Blockchain,ICO_ACCOUNTS,ico_key_pair};
 use crate::api::address::H160 as Address;
+use crate::basic::amount::Amount;
+use crate::basic::ledger::LedgerModel;
 use crate::api::miner::END_GENERATOR;
 pub struct TransactionGenerator {
     server: ServerHandle,
     mempool: Arc<Mutex<Mempool>>,
     blockchain: Arc<Mutex<Blockchain>>,
-    controlled_keypair: Ed25519KeyPair,
+    accounts: Vec<(Address,Ed25519KeyPair)>,//the genesis accounts and their keys
 }
 
 impl TransactionGenerator {
@@ -33,13 +36,16 @@ impl TransactionGenerator {
         server: &ServerHandle,
         mempool: &Arc<Mutex<Mempool>>,
         blockchain: &Arc<Mutex<Blockchain>>,
-        controlled_keypair: Ed25519KeyPair,
     ) -> TransactionGenerator {
+        let accounts=(1..=ICO_ACCOUNTS).map(|i| {
+            let key=ico_key_pair(i);
+            (Address::from_pubkey(key.public_key().as_ref()),key)
+        }).collect();
         TransactionGenerator {
             server: server.clone(),
             mempool: Arc::clone(mempool),
             blockchain: Arc::clone(blockchain),
-            controlled_keypair,
+            accounts,
         }
     }
 
@@ -54,13 +60,6 @@ impl TransactionGenerator {
         const INTERVAL_MILLISECONDS: u64 = 700; // how quickly to generate transactions
         const TRX_LIM:usize=100;
         let mut trans_cnt:usize=1;
-        //initiate account
-        let mut account_vec:Vec<Address>=Vec::new();
-        
-        for i in 1..6{
-            let addr_raw:[u8;20]=[i;20];
-            account_vec.push(Address::new(addr_raw));
-        }
         info!("start to generate loop!\n");
         while END_GENERATOR.load(Ordering::SeqCst){
 
@@ -76,33 +75,26 @@ impl TransactionGenerator {
             let mut rng = rand::thread_rng();
             let prob=rng.gen_range(0,100);
             if prob >97{
-                let transaction=generate_random_signed_transaction_with_key(&key_pair::random());
-                mempool.insert(transaction);
+                let transaction=generate_random_signed_transaction_with_key(&key_pair::random(),&blockchain.chain_id());
+                let _=mempool.insert(transaction.into(),blockchain.tip_state());
             }
             else{
             
-            let now_state=blockchain.get_tip_state();
-            let accounts=now_state.get_accounts();
-            let sender_id=rng.gen_range(0, accounts.len());
-            let (nonce,_)=accounts.get(&account_vec.get(sender_id).unwrap()).unwrap();
-            let sender_addr:Address=account_vec.get(sender_id).unwrap().clone();
-            let nonce:usize=nonce+1;
-            let value:usize=100;
-
-            let reciever_id=rng.gen_range(0,accounts.len());
-
-            let receiver_addr:Address=account_vec.get(reciever_id).unwrap().clone();
-            let trans_raw=Transaction{
-                sender:sender_addr,
-                receiver:receiver_addr,
-                value,
-                nonce,
+            let now_state=blockchain.tip_state();
+            let sender_id=rng.gen_range(0, self.accounts.len());
+            let reciever_id=rng.gen_range(0,self.accounts.len());
+            let value=Amount::from_base_units(100);
+            let fee=Amount::from_base_units(rng.gen_range(1,10));
+            let transaction=match now_state.model(){
+                LedgerModel::Account=>self.account_transaction(sender_id,reciever_id,value,fee,&blockchain,&mempool),
+                LedgerModel::Utxo=>match self.utxo_transaction(sender_id,reciever_id,value,fee,&blockchain,&mempool){
+                    Some(transaction)=>transaction,
+                    None=>continue,
+                },
             };
-            let transaction=SignedTransaction::from_raw(trans_raw,&self.controlled_keypair);
             
-            if transaction.verify_by_state(&now_state){
-                let trans_hash=transaction.hash();
-                mempool.insert(transaction);
+            let trans_hash=transaction.hash();
+            if mempool.insert(transaction,now_state).is_ok(){
                 let trans_vec=vec![trans_hash];
                 self.server.broadcast(Message::NewTransactionHashes(trans_vec));
                 trans_cnt+=1;
@@ -113,4 +105,36 @@ impl TransactionGenerator {
             //info!("generate a new transaction and broadcast to others! the new total number of transaction is {}\n",trans_cnt); 
         }
     }
+    /// A transfer between two genesis accounts, following on from the sender's transactions still waiting in the mempool
+    fn account_transaction(&self,sender_id:usize,reciever_id:usize,value:Amount,fee:Amount,blockchain:&Blockchain,mempool:&Mempool)->LedgerTransaction{
+        let (sender_addr,sender_key)=&self.accounts[sender_id];
+        let nonce:usize=mempool.next_nonce(sender_addr,blockchain.tip_state());
+        let trans_raw=Transaction{
+            sender:*sender_addr,
+            receiver:self.accounts[reciever_id].0,
+            value,
+            fee,
+            nonce,
+            valid_until_height:None,
+            kind:TransactionKind::Transfer,
+            memo:Vec::new(),
+        };
+        SignedTransaction::from_raw(trans_raw,&blockchain.chain_id(),sender_key).into()
+    }
+    /// A transfer between two genesis keys spending one of the sender's outputs that no transaction in the mempool spends yet,
+    /// with the change going back to the sender. `None` if the sender has no such output that covers it.
+    fn utxo_transaction(&self,sender_id:usize,reciever_id:usize,value:Amount,fee:Amount,blockchain:&Blockchain,mempool:&Mempool)->Option<LedgerTransaction>{
+        let (sender_addr,sender_key)=&self.accounts[sender_id];
+        let cost=value.checked_add(fee)?;
+        let (outpoint,output)=blockchain.tip_state().outputs_of(sender_addr).into_iter()
+            .find(|(outpoint,output)| !mempool.is_spent(outpoint) && output.value>=cost)?;
+        let mut outputs=vec![TxOutput{value,recipient:self.accounts[reciever_id].0}];
+        let change=output.value.checked_sub(cost)?;
+        if change>Amount::ZERO{
+            outputs.push(TxOutput{value:change,recipient:*sender_addr});
+        }
+        let mut transaction=UtxoTransaction::new(vec![outpoint],outputs,fee);
+        transaction.sign_input(0,&blockchain.chain_id(),sender_key);
+        Some(transaction.into())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,146 @@
+//! Transactions of the UTXO ledger model.
+//!
+//! A transaction spends unspent outputs of earlier transactions and creates new ones. Every input
+//! is signed on its own by the key of the output it spends; the signature covers all inputs and
+//! outputs, so no part of the transaction can be changed once it is signed.
+
+use crate::api::address::H160 as Address;
+use crate::basic::amount::Amount;
+use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::key_pair;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+
+/// The signing domain of UTXO inputs
+pub const UTXO_INPUT_DOMAIN: &[u8] = b"utxo-input";
+
+/// An output of a transaction: the transaction's hash and the output's position in it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OutPoint {
+    pub txid: H256,
+    pub index: u32,
+}
+
+/// Coins that the key behind `recipient` can spend
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxOutput {
+    pub value: Amount,
+    pub recipient: Address,
+}
+
+/// A spent output, with the key it belongs to and that key's signature
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxInput {
+    pub previous: OutPoint,
+    pub pub_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// A transaction of the UTXO model. The inputs must add up to the outputs plus the fee exactly.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct UtxoTransaction {
+    pub inputs: Vec<TxInput>,
+    pub outputs: Vec<TxOutput>,
+    pub fee: Amount,
+    pub valid_until_height: Option<u64>,
+}
+
+/// What the signature of input `index` covers: everything but the keys and signatures
+#[derive(Serialize)]
+struct SignedPart<'a> {
+    previous: Vec<&'a OutPoint>,
+    outputs: &'a [TxOutput],
+    fee: Amount,
+    valid_until_height: Option<u64>,
+    index: u32,
+}
+
+impl UtxoTransaction {
+    /// A transaction spending `previous`, to be signed with `sign_input`
+    pub fn new(previous: Vec<OutPoint>, outputs: Vec<TxOutput>, fee: Amount) -> Self {
+        let inputs = previous
+            .into_iter()
+            .map(|previous| TxInput { previous, pub_key: Vec::new(), signature: Vec::new() })
+            .collect();
+        UtxoTransaction { inputs, outputs, fee, valid_until_height: None }
+    }
+
+    /// The bytes the key of input `index` signs on the chain `chain_id`
+    pub fn signing_message(&self, index: usize, chain_id: &H256) -> Vec<u8> {
+        let part = SignedPart {
+            previous: self.inputs.iter().map(|input| &input.previous).collect(),
+            outputs: &self.outputs,
+            fee: self.fee,
+            valid_until_height: self.valid_until_height,
+            index: index as u32,
+        };
+        let bytes = bincode::serialize(&part).unwrap();
+        key_pair::signing_message(UTXO_INPUT_DOMAIN, chain_id, &bytes)
+    }
+
+    /// Sign input `index` with `key`, which has to own the output it spends
+    pub fn sign_input(&mut self, index: usize, chain_id: &H256, key: &Ed25519KeyPair) {
+        let signature = key.sign(&self.signing_message(index, chain_id));
+        let input = &mut self.inputs[index];
+        input.pub_key = key.public_key().as_ref().to_vec();
+        input.signature = signature.as_ref().to_vec();
+    }
+
+    /// Whether every input is signed by its attached key on the chain `chain_id`.
+    /// That the keys own the spent outputs can only be checked against the ledger.
+    pub fn verify_signatures(&self, chain_id: &H256) -> bool {
+        self.inputs.iter().enumerate().all(|(index, input)| {
+            let public_key = UnparsedPublicKey::new(&ED25519, &input.pub_key[..]);
+            public_key.verify(&self.signing_message(index, chain_id), &input.signature).is_ok()
+        })
+    }
+
+    /// Output `index` of this transaction
+    pub fn outpoint(&self, index: usize) -> OutPoint {
+        OutPoint { txid: self.hash(), index: index as u32 }
+    }
+
+    /// The sum of the outputs and the fee, or `None` if it is above the supply
+    pub fn spent(&self) -> Option<Amount> {
+        Amount::checked_sum(self.outputs.iter().map(|output| output.value).chain(Some(self.fee)))
+    }
+}
+
+impl Hashable for UtxoTransaction {
+    fn hash(&self) -> H256 {
+        let bytes = bincode::serialize(&self).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &bytes).into()
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::tests::generate_random_hash;
+
+    #[test]
+    fn every_input_is_signed() {
+        let (alice, bob) = (key_pair::random(), key_pair::random());
+        let chain_id = generate_random_hash();
+        let previous = vec![
+            OutPoint { txid: generate_random_hash(), index: 0 },
+            OutPoint { txid: generate_random_hash(), index: 3 },
+        ];
+        let outputs = vec![TxOutput { value: Amount::from_base_units(10), recipient: Address::new([9; 20]) }];
+        let mut transaction = UtxoTransaction::new(previous, outputs, Amount::from_base_units(1));
+        transaction.sign_input(0, &chain_id, &alice);
+        assert!(!transaction.verify_signatures(&chain_id));
+        transaction.sign_input(1, &chain_id, &bob);
+        assert!(transaction.verify_signatures(&chain_id));
+        assert!(!transaction.verify_signatures(&generate_random_hash()));
+
+        // a signature does not carry over to another input or to changed outputs
+        let mut swapped = transaction.clone();
+        swapped.inputs.swap(0, 1);
+        assert!(!swapped.verify_signatures(&chain_id));
+        let mut changed = transaction.clone();
+        changed.outputs[0].recipient = Address::new([8; 20]);
+        assert!(!changed.verify_signatures(&chain_id));
+        assert_eq!(transaction.spent(), Some(Amount::from_base_units(11)));
+    }
+}